@@ -0,0 +1,593 @@
+use crate::lock::MirrorLock;
+use crate::storage::{FsStorage, Storage};
+use crate::{
+    cancel, file_sha256, manifest_artifacts, parse_size, publish_manifest, url_to_path, Cli,
+    RELEASE_CHANNELS, TARGETS,
+};
+use anyhow::{anyhow, Error};
+use clap::Args;
+use ring::digest;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{create_dir_all, read_to_string, remove_file, rename, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use toml::Value;
+
+/// Name of the checksum index at the top of every bundle
+pub const INDEX_NAME: &str = "SHA256SUMS";
+
+/// Rustup release manifest, next to the binaries of that release
+const RELEASE_NAME: &str = "rustup/release-stable.toml";
+
+#[derive(Args)]
+pub struct ImportArgs {
+    /// Bundle to import, volumes are picked up by their .000, .001, ... suffix
+    bundle: PathBuf,
+
+    /// Wait for another run holding the mirror lock instead of failing
+    #[arg(long)]
+    wait: bool,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Where to write the bundle, volumes get a .000, .001, ... suffix
+    output: PathBuf,
+
+    /// Which release channel(s) or versions to export, e.g. stable,1.80.0
+    #[arg(short, long, value_delimiter = ',', default_values_t = RELEASE_CHANNELS.map(String::from))]
+    channels: Vec<String>,
+
+    /// Which targets to export, e.g. x86_64-unknown-linux-gnu,x86_64-apple-darwin
+    #[arg(short, long, value_delimiter = ',', default_values_t = TARGETS.map(String::from))]
+    targets: Vec<String>,
+
+    /// Split the bundle into volumes of at most this size, e.g. 4G
+    #[arg(long, value_parser = parse_size)]
+    volume_size: Option<u64>,
+
+    /// Only export what changed since the export that saved this state file
+    #[arg(long)]
+    since: Option<PathBuf>,
+}
+
+/// Writes a byte stream into numbered volumes of bounded size
+struct VolumeWriter {
+    base: PathBuf,
+    volume_size: u64,
+    index: usize,
+    written: u64,
+    file: Option<File>,
+}
+
+impl VolumeWriter {
+    fn new(base: &Path, volume_size: u64) -> Self {
+        VolumeWriter {
+            base: base.to_path_buf(),
+            volume_size,
+            index: 0,
+            written: 0,
+            file: None,
+        }
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() || self.written == self.volume_size {
+            let path = volume_path(&self.base, self.index);
+            println!("Writing volume {}", path.display());
+            self.file = Some(File::create(path)?);
+            self.index += 1;
+            self.written = 0;
+        }
+
+        let len = buf.len().min((self.volume_size - self.written) as usize);
+        let len = self.file.as_mut().unwrap().write(&buf[..len])?;
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads numbered volumes back as one byte stream
+struct VolumeReader {
+    base: PathBuf,
+    index: usize,
+    file: File,
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.file.read(buf)?;
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+
+            let next = volume_path(&self.base, self.index + 1);
+            if !next.exists() {
+                return Ok(0);
+            }
+            println!("Reading volume {}", next.display());
+            self.file = File::open(next)?;
+            self.index += 1;
+        }
+    }
+}
+
+/// Passes writes through while computing their sha256
+struct HashingWriter<W> {
+    inner: W,
+    context: digest::Context,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            context: digest::Context::new(&digest::SHA256),
+        }
+    }
+
+    fn finish(self) -> String {
+        hex::encode(self.context.finish())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.context.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn volume_path(base: &Path, index: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_owned();
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+/// Manifests and the files they cover, exported together
+#[derive(Default)]
+struct ExportGroup {
+    manifests: Vec<(String, PathBuf)>,
+    files: Vec<(String, PathBuf)>,
+}
+
+/// Read a state file, which uses the same format as the bundle index
+fn read_state(path: &Path) -> Result<HashSet<String>, Error> {
+    Ok(read_to_string(path)?.lines().map(String::from).collect())
+}
+
+pub fn export(cli: &Cli, args: &ExportArgs) -> Result<(), Error> {
+    let orig = Path::new(&cli.orig);
+    let mirror = Path::new(&cli.mirror);
+    let filter_targets = args.targets.iter().collect::<HashSet<_>>();
+    let since = args.since.as_deref().map(read_state).transpose()?;
+
+    // Paths inside the bundle and on disk, grouped by manifest
+    let mut groups = vec![];
+
+    for channel in &args.channels {
+        let name = format!("dist/channel-rust-{}.toml", channel);
+        let published_path = mirror.join(&name);
+        if !published_path.exists() {
+            println!("Channel {} has not been synced, skipping", channel);
+            continue;
+        }
+
+        // Files come from the published manifest, which only lists what was
+        // mirrored, while the upstream one goes along for verification
+        let value: Value = toml::from_str(&read_to_string(&published_path)?)?;
+        let date = value["date"].as_str().unwrap_or("unknown");
+        println!("Exporting channel {} date {}", channel, date);
+        let manifest_dir = match read_to_string(orig.join(&name)) {
+            Ok(data) if toml::from_str::<Value>(&data)?["date"].as_str() == Some(date) => orig,
+            // Versioned channels never reach orig, and the published
+            // manifest keeps the upstream checksums
+            _ => mirror,
+        };
+        let mut group = ExportGroup::default();
+        for name in [name.clone(), format!("{}.sha256", name)] {
+            group
+                .manifests
+                .push((format!("orig/{}", name), manifest_dir.join(&name)));
+        }
+
+        for (file_name, _) in manifest_artifacts(&value, Some(&filter_targets))? {
+            let path = mirror.join(&file_name);
+            if !path.exists() {
+                return Err(anyhow!(
+                    "File /{} of channel {} is missing from the mirror, sync it first",
+                    file_name,
+                    channel
+                ));
+            }
+            group.files.push((format!("mirror/{}", file_name), path));
+        }
+        groups.push(group);
+    }
+
+    if groups.is_empty() {
+        return Err(anyhow!("Nothing to export"));
+    }
+
+    // Rustup binaries are optional, sync ignores targets without one
+    if let Ok(release) = read_to_string(orig.join(RELEASE_NAME)) {
+        let mut group = ExportGroup::default();
+        group
+            .manifests
+            .push((format!("orig/{}", RELEASE_NAME), orig.join(RELEASE_NAME)));
+        let version = toml::from_str::<Value>(&release)?["version"]
+            .as_str()
+            .map(String::from);
+        for target in &args.targets {
+            let ext = if target.contains("windows") {
+                ".exe"
+            } else {
+                ""
+            };
+            let mut names = vec![format!("rustup/dist/{}/rustup-init{}", target, ext)];
+            if let Some(version) = &version {
+                names.push(format!(
+                    "rustup/archive/{}/{}/rustup-init{}",
+                    version, target, ext
+                ));
+            }
+            for name in names {
+                let path = mirror.join(&name);
+                if path.exists() {
+                    group.files.push((format!("mirror/{}", name), path));
+                }
+            }
+        }
+        groups.push(group);
+    }
+
+    // Index lines of everything currently exportable, which is also the new state
+    let mut lines = HashMap::new();
+    for (name, path) in groups
+        .iter()
+        .flat_map(|group| group.manifests.iter().chain(group.files.iter()))
+    {
+        let sha256 = file_sha256(path).ok_or_else(|| anyhow!("Unable to read {}", name))?;
+        lines.insert(name.clone(), format!("{}  {}", sha256, name));
+    }
+    let changed = |name: &String| {
+        since
+            .as_ref()
+            .is_none_or(|since| !since.contains(&lines[name]))
+    };
+
+    // Manifests go along with any of their files, so importers can verify them
+    let mut manifests = BTreeMap::new();
+    let mut files = BTreeMap::new();
+    for group in groups {
+        let group_files = group
+            .files
+            .into_iter()
+            .filter(|(name, _)| changed(name))
+            .collect::<Vec<_>>();
+        if !group_files.is_empty() || group.manifests.iter().any(|(name, _)| changed(name)) {
+            manifests.extend(group.manifests);
+        }
+        files.extend(group_files);
+    }
+
+    let mut index = String::new();
+    for name in manifests.keys().chain(files.keys()) {
+        index.push_str(&lines[name]);
+        index.push('\n');
+    }
+
+    let writer: Box<dyn Write> = match args.volume_size {
+        Some(0) => return Err(anyhow!("Volume size must not be zero")),
+        Some(volume_size) => Box::new(VolumeWriter::new(&args.output, volume_size)),
+        None => Box::new(File::create(&args.output)?),
+    };
+    let mut builder = tar::Builder::new(writer);
+
+    // The index comes first, followed by manifests, so that importers can
+    // verify artifacts while streaming
+    let mut header = tar::Header::new_gnu();
+    header.set_size(index.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, INDEX_NAME, index.as_bytes())?;
+
+    for (name, path) in manifests.iter().chain(files.iter()) {
+        println!("Adding /{}", name);
+        builder.append_path_with_name(path, name)?;
+    }
+
+    builder.into_inner()?.flush()?;
+    println!(
+        "Exported {} manifests and {} files into {}",
+        manifests.len(),
+        files.len(),
+        args.output.display()
+    );
+
+    let mut state = lines.into_values().collect::<Vec<_>>();
+    state.sort();
+    let state_path = state_path(&args.output);
+    File::create(&state_path)?.write_all((state.join("\n") + "\n").as_bytes())?;
+    println!("Saved export state to {}", state_path.display());
+    Ok(())
+}
+
+/// Where the state of an export is saved, for a later `--since`
+fn state_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".state");
+    PathBuf::from(name)
+}
+
+fn open_bundle(path: &Path) -> Result<Box<dyn Read>, Error> {
+    if path.exists() {
+        return Ok(Box::new(File::open(path)?));
+    }
+
+    let first = volume_path(path, 0);
+    if !first.exists() {
+        return Err(anyhow!("Bundle {} not found", path.display()));
+    }
+    println!("Reading volume {}", first.display());
+    Ok(Box::new(VolumeReader {
+        base: path.to_path_buf(),
+        index: 0,
+        file: File::open(first)?,
+    }))
+}
+
+/// Path of a bundle entry, refusing anything that could escape the mirror
+fn entry_name<R: Read>(entry: &tar::Entry<R>) -> Result<String, Error> {
+    let path = entry.path()?;
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow!("Refusing bundle entry {}", path.display()));
+    }
+    path.to_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("Bundle entry {} is not valid UTF-8", path.display()))
+}
+
+/// Checksum of a file in the mirror, preferring its sidecar like sync does
+fn mirror_sha256(mirror: &Path, file_name: &str) -> Option<String> {
+    read_to_string(mirror.join(format!("{}.sha256", file_name)))
+        .ok()
+        .or_else(|| file_sha256(&mirror.join(file_name)))
+}
+
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Whether the rustup release of a bundle is at least as new as the one in
+/// the mirror, a bundle without one does not hold any rustup binaries
+fn rustup_is_current(mirror: &Path, release: Option<&Vec<u8>>) -> Result<bool, Error> {
+    let Some(release) = release else {
+        return Ok(true);
+    };
+    let version = |data: &str| {
+        toml::from_str::<Value>(data)
+            .ok()
+            .and_then(|release| release["version"].as_str().map(version_key))
+    };
+    let current = read_to_string(mirror.join(RELEASE_NAME))
+        .ok()
+        .and_then(|data| version(&data));
+    Ok(current <= version(std::str::from_utf8(release)?))
+}
+
+pub fn import(cli: &Cli, args: &ImportArgs) -> Result<(), Error> {
+    let _lock = MirrorLock::acquire(&cli.mirror, args.wait)?;
+    let mirror = Path::new(&cli.mirror);
+    // Files may be linked from a local upstream, so they are replaced rather
+    // than written in place
+    let storage = FsStorage::new(&cli.mirror);
+    let orig_storage = FsStorage::new(&cli.orig);
+    let mut archive = tar::Archive::new(open_bundle(&args.bundle)?);
+    let mut entries = archive.entries()?;
+
+    let mut index = HashMap::new();
+    {
+        let mut entry = entries.next().ok_or_else(|| anyhow!("Bundle is empty"))??;
+        if entry_name(&entry)? != INDEX_NAME {
+            return Err(anyhow!("Bundle does not start with {}", INDEX_NAME));
+        }
+        let mut data = String::new();
+        entry.read_to_string(&mut data)?;
+        for line in data.lines() {
+            let (sha256, name) = line
+                .split_once("  ")
+                .ok_or_else(|| anyhow!("Malformed {} line: {}", INDEX_NAME, line))?;
+            index.insert(name.to_string(), sha256.to_string());
+        }
+    }
+
+    // Original manifests, and checksums of every artifact they reference
+    let mut manifests = BTreeMap::new();
+    let mut expected = HashMap::new();
+    let mut imported = 0;
+
+    // Manifests are only published once every entry is in, so a cancelled
+    // import leaves the mirror as it was
+    for entry in entries {
+        cancel::check()?;
+        let mut entry = entry?;
+        let name = entry_name(&entry)?;
+        let indexed = index
+            .get(&name)
+            .ok_or_else(|| anyhow!("Bundle entry {} is missing from the index", name))?;
+
+        if let Some(file_name) = name.strip_prefix("orig/") {
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            if hex::encode(digest::digest(&digest::SHA256, &data)) != *indexed {
+                return Err(anyhow!("Checksum mismatch for manifest {}", name));
+            }
+            if file_name.starts_with("dist/channel-rust-") && file_name.ends_with(".toml") {
+                let value: Value = toml::from_str(std::str::from_utf8(&data)?)?;
+                expected.extend(manifest_artifacts(&value, None)?);
+            }
+            manifests.insert(file_name.to_string(), data);
+        } else if let Some(file_name) = name.strip_prefix("mirror/") {
+            if file_name.starts_with("rustup/dist/")
+                && !rustup_is_current(mirror, manifests.get(RELEASE_NAME))?
+            {
+                println!("Rustup in the mirror is newer, keeping /{}", file_name);
+                continue;
+            }
+            let sha256 = match expected.get(file_name) {
+                Some(sha256) => sha256,
+                // Rustup binaries are not listed in channel manifests
+                None if file_name.starts_with("rustup/") => indexed,
+                None => {
+                    return Err(anyhow!(
+                        "File /{} is not referenced by any manifest in the bundle",
+                        file_name
+                    ));
+                }
+            };
+
+            let path = mirror.join(file_name);
+            let part = mirror.join(format!("{}.part", file_name));
+            create_dir_all(path.parent().unwrap())?;
+            let mut writer = HashingWriter::new(File::create(&part)?);
+            io::copy(&mut entry, &mut writer)?;
+            if writer.finish() != *sha256 {
+                remove_file(&part)?;
+                return Err(anyhow!("Checksum mismatch for file /{}", file_name));
+            }
+            rename(&part, &path)?;
+
+            if expected.contains_key(file_name) {
+                storage.put(&format!("{}.sha256", file_name), sha256.as_bytes())?;
+            }
+            println!("File /{} imported", file_name);
+            imported += 1;
+        } else {
+            return Err(anyhow!("Unexpected bundle entry {}", name));
+        }
+    }
+
+    for (file_name, data) in &manifests {
+        let Some(channel) = file_name
+            .strip_prefix("dist/channel-rust-")
+            .and_then(|name| name.strip_suffix(".toml"))
+        else {
+            continue;
+        };
+
+        let mut value: Value = toml::from_str(std::str::from_utf8(data)?)?;
+        let date = value["date"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Manifest of channel {} has no date", channel))?
+            .to_string();
+
+        // Targets whose files did not come with the bundle or an earlier
+        // import are marked unavailable, just like filtered targets in sync
+        let pkgs = value["pkg"]
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("Manifest has no pkg table"))?;
+        for (_, pkg) in pkgs.iter_mut() {
+            let pkg_targets = pkg
+                .get_mut("target")
+                .and_then(Value::as_table_mut)
+                .ok_or_else(|| anyhow!("Package has no target table"))?;
+            for (_, pkg_target) in pkg_targets.iter_mut() {
+                let pkg_target = pkg_target
+                    .as_table_mut()
+                    .ok_or_else(|| anyhow!("Malformed target table"))?;
+                if pkg_target.get("available").and_then(Value::as_bool) != Some(true) {
+                    continue;
+                }
+
+                let mut urls = vec![];
+                for prefix in ["", "xz_"] {
+                    let (Some(url), Some(hash)) = (
+                        pkg_target
+                            .get(&format!("{}url", prefix))
+                            .and_then(Value::as_str),
+                        pkg_target
+                            .get(&format!("{}hash", prefix))
+                            .and_then(Value::as_str),
+                    ) else {
+                        continue;
+                    };
+                    let file_name = format!("/{}", url_to_path(url)?);
+                    if mirror_sha256(mirror, &file_name[1..]).as_deref() != Some(hash) {
+                        urls.clear();
+                        break;
+                    }
+                    urls.push((format!("{}url", prefix), file_name));
+                }
+
+                if urls.is_empty() {
+                    pkg_target.insert("available".to_string(), Value::Boolean(false));
+                }
+                for (key, file_name) in urls {
+                    pkg_target.insert(key, Value::String(format!("{}{}", cli.url, file_name)));
+                }
+            }
+        }
+
+        let head = mirror.join(format!("dist/channel-rust-{}.toml", channel));
+        let head_date = read_to_string(&head)
+            .ok()
+            .and_then(|data| toml::from_str::<Value>(&data).ok())
+            .and_then(|head| head["date"].as_str().map(String::from));
+        let update_head = head_date
+            .as_ref()
+            .is_none_or(|head_date| *head_date <= date);
+        if !update_head {
+            println!(
+                "Channel {} in the mirror is newer ({} > {}), keeping its head",
+                channel,
+                head_date.unwrap(),
+                date
+            );
+        }
+        publish_manifest(&storage, channel, &value, update_head)?;
+
+        if update_head {
+            for name in [file_name.clone(), format!("{}.sha256", file_name)] {
+                if let Some(data) = manifests.get(&name) {
+                    orig_storage.put(&name, data)?;
+                }
+            }
+        }
+    }
+
+    if let Some(data) = manifests.get(RELEASE_NAME) {
+        if rustup_is_current(mirror, Some(data))? {
+            orig_storage.put(RELEASE_NAME, data)?;
+            storage.put(RELEASE_NAME, data)?;
+            println!("Producing /{}", RELEASE_NAME);
+        } else {
+            println!("Rustup in the mirror is newer, keeping /{}", RELEASE_NAME);
+        }
+    }
+
+    println!("Imported {} files from {}", imported, args.bundle.display());
+    Ok(())
+}
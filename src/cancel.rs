@@ -0,0 +1,53 @@
+use anyhow::Error;
+use std::fmt;
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Exit status of a run stopped by SIGINT or SIGTERM
+pub const EXIT_CANCELLED: i32 = 130;
+
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+/// Error returned by work stopped because of a signal
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Handle SIGINT and SIGTERM: the first one cancels the running work,
+/// the second one exits immediately
+pub fn install() -> Result<(), Error> {
+    ctrlc::set_handler(|| {
+        if SIGNALS.fetch_add(1, Ordering::SeqCst) == 0 {
+            eprintln!("Cancelling, send the signal again to exit immediately");
+        } else {
+            eprintln!("Exiting immediately");
+            exit(EXIT_CANCELLED);
+        }
+    })?;
+    Ok(())
+}
+
+pub fn is_cancelled() -> bool {
+    SIGNALS.load(Ordering::SeqCst) > 0
+}
+
+/// Fail with `Cancelled` once a signal was received
+pub fn check() -> Result<(), Error> {
+    if is_cancelled() {
+        Err(Cancelled.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether an error comes from cancellation
+pub fn is_cancel_error(err: &Error) -> bool {
+    err.is::<Cancelled>()
+}
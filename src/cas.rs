@@ -0,0 +1,131 @@
+use crate::file_sha256;
+use anyhow::Error;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fs::{create_dir_all, hard_link, read_link, remove_file, rename, symlink_metadata};
+use std::path::{Path, PathBuf};
+
+/// Directory of the content-addressed store inside the mirror
+pub const CAS_DIR: &str = ".cas/sha256";
+
+/// How public paths refer to objects in the content-addressed store
+#[derive(Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CasMode {
+    Hardlink,
+    Symlink,
+}
+
+/// Path of the object with some sha256 checksum
+pub fn object_path(mirror: &Path, sha256: &str) -> PathBuf {
+    mirror.join(CAS_DIR).join(&sha256[..2]).join(sha256)
+}
+
+/// Link the stored object into a public path, if it is stored
+pub fn link_stored(mirror: &Path, path: &str, sha256: &str, mode: CasMode) -> Result<bool, Error> {
+    let object = object_path(mirror, sha256);
+    if !object.is_file() {
+        return Ok(false);
+    }
+    let file = mirror.join(path);
+    create_dir_all(file.parent().unwrap())?;
+    if symlink_metadata(&file).is_ok() {
+        remove_file(&file)?;
+    }
+    link(mirror, path, &object, mode)?;
+    Ok(true)
+}
+
+/// Move a verified file at a public path into the store and link it back,
+/// or replace it with a link if the store already has the same content
+pub fn store(mirror: &Path, path: &str, sha256: &str, mode: CasMode) -> Result<(), Error> {
+    let file = mirror.join(path);
+    let object = object_path(mirror, sha256);
+    if object.is_file() {
+        if is_linked(&file, &object, mode)? {
+            return Ok(());
+        }
+        remove_file(&file)?;
+    } else {
+        create_dir_all(object.parent().unwrap())?;
+        rename(&file, &object)?;
+    }
+    link(mirror, path, &object, mode)
+}
+
+/// Remove a stored object whose content does not match its checksum, so that
+/// it is never linked again
+pub fn drop_corrupt(mirror: &Path, sha256: &str) -> Result<(), Error> {
+    let object = object_path(mirror, sha256);
+    if object.is_file() && file_sha256(&object).as_deref() != Some(sha256) {
+        println!("Removing corrupt object {}", object.display());
+        remove_file(object)?;
+    }
+    Ok(())
+}
+
+/// Checksum of the object a public symlink points to
+pub fn symlink_target(file: &Path) -> Option<String> {
+    let target = read_link(file).ok()?;
+    let name = target.file_name()?.to_string_lossy().into_owned();
+    target.to_string_lossy().contains(CAS_DIR).then_some(name)
+}
+
+fn link(mirror: &Path, path: &str, object: &Path, mode: CasMode) -> Result<(), Error> {
+    let file = mirror.join(path);
+    match mode {
+        CasMode::Hardlink => hard_link(object, &file)?,
+        CasMode::Symlink => {
+            // Relative, so the mirror can be moved or served from anywhere
+            let depth = Path::new(path).components().count() - 1;
+            let target = Path::new(&"../".repeat(depth)).join(object.strip_prefix(mirror).unwrap());
+            symlink(&target, &file)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, file: &Path) -> Result<(), Error> {
+    Ok(std::os::unix::fs::symlink(target, file)?)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, file: &Path) -> Result<(), Error> {
+    Ok(std::os::windows::fs::symlink_file(target, file)?)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_target: &Path, _file: &Path) -> Result<(), Error> {
+    Err(anyhow::anyhow!(
+        "Symlinks are not supported on this platform"
+    ))
+}
+
+/// Whether a public file already refers to an object
+fn is_linked(file: &Path, object: &Path, mode: CasMode) -> Result<bool, Error> {
+    match mode {
+        CasMode::Symlink => Ok(symlink_target(file).is_some_and(|sha256| {
+            object
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy() == sha256)
+        })),
+        CasMode::Hardlink => same_file(file, object),
+    }
+}
+
+#[cfg(unix)]
+fn same_file(file: &Path, object: &Path) -> Result<bool, Error> {
+    use std::os::unix::fs::MetadataExt;
+    let (file, object) = (symlink_metadata(file)?, symlink_metadata(object)?);
+    Ok(file.dev() == object.dev() && file.ino() == object.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(file: &Path, _object: &Path) -> Result<bool, Error> {
+    // Without inode numbers, relinking is the safe choice
+    if !file.exists() {
+        return Err(anyhow::anyhow!("File {} does not exist", file.display()));
+    }
+    Ok(false)
+}
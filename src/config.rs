@@ -0,0 +1,375 @@
+use crate::cas::CasMode;
+use crate::{
+    gc, gpg, parse_size, Cli, Command, RetentionArgs, SpacePolicy, StorageArgs, SyncArgs,
+    UpstreamArgs, TARGETS,
+};
+use anyhow::{anyhow, Context, Error};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Subcommand};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the file given by --config
+    Check,
+}
+
+/// Settings read from the file given by `--config`, overridden by command line flags.
+/// Each named profile has the same settings, falling back to the top-level ones.
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    orig: Option<String>,
+    mirror: Option<String>,
+    url: Option<String>,
+    upstream_url: Option<Vec<String>>,
+    channels: Option<Vec<String>>,
+    /// Versions such as 1.80.0, mirrored in addition to the channels
+    #[serde(default)]
+    versions: Vec<String>,
+    targets: Option<Vec<String>>,
+    components: Option<Vec<String>>,
+    /// Download cache shared by all profiles
+    cache: Option<String>,
+    /// Size quota of the mirror, e.g. 500G
+    max_size: Option<String>,
+    space_policy: Option<SpacePolicy>,
+    /// Store files in a content-addressed store, linked by hardlink or symlink
+    cas: Option<CasMode>,
+    /// Publish into an S3-compatible bucket, e.g. s3://bucket/prefix
+    storage: Option<String>,
+    s3: Option<S3Config>,
+    /// Check the GPG signature of channel manifests
+    verify_signatures: Option<bool>,
+    /// Armored public key the manifests are signed with, the Rust release key by default
+    gpg_key: Option<PathBuf>,
+    #[serde(default)]
+    gc: GcConfig,
+    network: Option<NetworkConfig>,
+    profiles: Option<BTreeMap<String, Config>>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct GcConfig {
+    /// Keep how many days of nightly toolchains
+    nightly_days: Option<i64>,
+    keep_nightlies: Option<usize>,
+    keep_betas: Option<usize>,
+    /// Keep stable releases since this version, e.g. 1.70
+    keep_stable_since: Option<String>,
+    pin_file: Option<PathBuf>,
+    keep_rustup_versions: Option<usize>,
+    /// Keep how many distinct upstream manifests of each channel in orig
+    orig_history: Option<usize>,
+    /// Delete files nothing kept refers to
+    sweep: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct S3Config {
+    endpoint: Option<String>,
+    region: Option<String>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct NetworkConfig {
+    proxy: Option<String>,
+    /// Timeout of each request in seconds
+    timeout: Option<u64>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data = read_to_string(path)
+            .with_context(|| format!("Unable to read config file {}", path.display()))?;
+        let config: Config = toml::from_str(&data)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        config.validate()?;
+        for (name, profile) in config.profiles.iter().flatten() {
+            if profile.profiles.is_some() || profile.network.is_some() {
+                return Err(anyhow!(
+                    "Profile {} can not have profiles or network settings",
+                    name
+                ));
+            }
+            config
+                .profile(name)?
+                .validate()
+                .with_context(|| format!("Invalid profile {}", name))?;
+        }
+        Ok(config)
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        self.profiles
+            .iter()
+            .flatten()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Settings of a named profile, with the top-level settings as fallback
+    pub fn profile(&self, name: &str) -> Result<Config, Error> {
+        let profile = self
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .ok_or_else(|| anyhow!("Profile {} not found in config file", name))?
+            .clone();
+        let top = self.clone();
+        Ok(Config {
+            orig: profile.orig.or(top.orig),
+            mirror: profile.mirror.or(top.mirror),
+            url: profile.url.or(top.url),
+            upstream_url: profile.upstream_url.or(top.upstream_url),
+            channels: profile.channels.or(top.channels),
+            versions: if profile.versions.is_empty() {
+                top.versions
+            } else {
+                profile.versions
+            },
+            targets: profile.targets.or(top.targets),
+            components: profile.components.or(top.components),
+            cache: profile.cache.or(top.cache),
+            max_size: profile.max_size.or(top.max_size),
+            space_policy: profile.space_policy.or(top.space_policy),
+            cas: profile.cas.or(top.cas),
+            storage: profile.storage.or(top.storage),
+            s3: profile.s3.or(top.s3),
+            verify_signatures: profile.verify_signatures.or(top.verify_signatures),
+            gpg_key: profile.gpg_key.or(top.gpg_key),
+            gc: GcConfig {
+                nightly_days: profile.gc.nightly_days.or(top.gc.nightly_days),
+                keep_nightlies: profile.gc.keep_nightlies.or(top.gc.keep_nightlies),
+                keep_betas: profile.gc.keep_betas.or(top.gc.keep_betas),
+                keep_stable_since: profile.gc.keep_stable_since.or(top.gc.keep_stable_since),
+                pin_file: profile.gc.pin_file.or(top.gc.pin_file),
+                keep_rustup_versions: profile
+                    .gc
+                    .keep_rustup_versions
+                    .or(top.gc.keep_rustup_versions),
+                orig_history: profile.gc.orig_history.or(top.gc.orig_history),
+                sweep: profile.gc.sweep.or(top.gc.sweep),
+            },
+            network: top.network,
+            profiles: None,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(url) = &self.url {
+            Url::parse(url).with_context(|| format!("Invalid url {}", url))?;
+        }
+        for upstream_url in self.upstream_url.iter().flatten() {
+            // Anything that is not a url is taken as a local directory
+            if Url::parse(upstream_url).is_err() && !Path::new(upstream_url).is_dir() {
+                return Err(anyhow!(
+                    "Upstream {} is not a url or a directory",
+                    upstream_url
+                ));
+            }
+        }
+        if self.upstream_url.as_ref().is_some_and(Vec::is_empty) {
+            return Err(anyhow!("At least one upstream url is required"));
+        }
+        if self.channels.as_ref().is_some_and(Vec::is_empty) && self.versions.is_empty() {
+            return Err(anyhow!("At least one channel or version is required"));
+        }
+        for target in self.targets.iter().flatten() {
+            if !TARGETS.contains(&target.as_str()) {
+                println!("Warning: target {} is not a known target", target);
+            }
+        }
+        if let Some(max_size) = &self.max_size {
+            parse_size(max_size).with_context(|| format!("Invalid max-size {}", max_size))?;
+        }
+        if let Some(storage) = &self.storage
+            && !Url::parse(storage).is_ok_and(|url| url.scheme() == "s3")
+        {
+            return Err(anyhow!("Storage {} is not an s3:// url", storage));
+        }
+        if let Some(endpoint) = self.s3.as_ref().and_then(|s3| s3.endpoint.as_ref()) {
+            Url::parse(endpoint).with_context(|| format!("Invalid S3 endpoint {}", endpoint))?;
+        }
+        if let Some(gpg_key) = &self.gpg_key {
+            gpg::load_key(Some(gpg_key))?;
+        }
+        if self.gc.nightly_days.is_some_and(|days| days < 0) {
+            return Err(anyhow!("gc.nightly-days must not be negative"));
+        }
+        if let Some(version) = &self.gc.keep_stable_since {
+            gc::parse_version(version).context("Invalid gc.keep-stable-since")?;
+        }
+        if let Some(pin_file) = &self.gc.pin_file {
+            gc::read_pins(pin_file)?;
+        }
+        if let Some(proxy) = self
+            .network
+            .as_ref()
+            .and_then(|network| network.proxy.as_ref())
+        {
+            reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy {}", proxy))?;
+        }
+        Ok(())
+    }
+
+    /// Fill in every setting that was not given on the command line
+    pub fn apply(&self, cli: &mut Cli, matches: &ArgMatches) {
+        if let Some(orig) = self.orig.clone().filter(|_| !given(matches, "orig")) {
+            cli.orig = orig;
+        }
+        if let Some(mirror) = self.mirror.clone().filter(|_| !given(matches, "mirror")) {
+            cli.mirror = mirror;
+        }
+        if let Some(url) = self.url.clone().filter(|_| !given(matches, "url")) {
+            cli.url = url;
+        }
+
+        match &mut cli.command {
+            None => self.apply_sync(&mut cli.sync, matches),
+            Some(Command::Sync(args)) => self.apply_sync(args, matches),
+            Some(Command::Daemon(args)) => self.apply_sync(&mut args.sync, matches),
+            Some(Command::Gc(args)) => {
+                self.apply_storage(&mut args.storage, matches);
+                self.apply_retention(&mut args.retention, matches);
+                if !given(matches, "channels") {
+                    args.channels = self.channels.clone().unwrap_or_default();
+                    args.channels.extend(self.versions.iter().cloned());
+                }
+            }
+            Some(Command::Verify(args)) => {
+                self.apply_storage(&mut args.storage, matches);
+                self.apply_upstream(&mut args.upstream, matches);
+                if !given(matches, "cas") {
+                    args.cas = self.cas.or(args.cas);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn apply_sync(&self, args: &mut SyncArgs, matches: &ArgMatches) {
+        self.apply_upstream(&mut args.upstream, matches);
+        self.apply_retention(&mut args.retention, matches);
+        self.apply_storage(&mut args.storage, matches);
+        if !given(matches, "channels") {
+            let mut channels = self
+                .channels
+                .clone()
+                .unwrap_or_else(|| args.channels.clone());
+            channels.extend(self.versions.iter().cloned());
+            args.channels = channels;
+        }
+        if let Some(targets) = self.targets.clone().filter(|_| !given(matches, "targets")) {
+            args.targets = targets;
+        }
+        if !given(matches, "components") {
+            args.components = self.components.clone().or(args.components.take());
+        }
+        if !given(matches, "verify_signatures") {
+            args.verify_signatures = self.verify_signatures.unwrap_or(args.verify_signatures);
+        }
+        if !given(matches, "gpg_key") {
+            args.gpg_key = self.gpg_key.clone().or(args.gpg_key.take());
+        }
+        if !given(matches, "max_size") {
+            // Checked by validate
+            let max_size = self
+                .max_size
+                .as_ref()
+                .and_then(|size| parse_size(size).ok());
+            args.max_size = max_size.or(args.max_size);
+        }
+        if let Some(space_policy) = self
+            .space_policy
+            .filter(|_| !given(matches, "space_policy"))
+        {
+            args.space_policy = space_policy;
+        }
+        if !given(matches, "cas") {
+            args.cas = self.cas.or(args.cas);
+        }
+    }
+
+    fn apply_upstream(&self, args: &mut UpstreamArgs, matches: &ArgMatches) {
+        if let Some(upstream_url) = self
+            .upstream_url
+            .clone()
+            .filter(|_| !given(matches, "upstream_url"))
+        {
+            args.upstream_url = upstream_url;
+        }
+        let network = self.network.clone().unwrap_or_default();
+        if !given(matches, "proxy") {
+            args.proxy = network.proxy.or(args.proxy.take());
+        }
+        if !given(matches, "timeout") {
+            args.timeout = network.timeout.or(args.timeout);
+        }
+    }
+
+    fn apply_retention(&self, args: &mut RetentionArgs, matches: &ArgMatches) {
+        let gc = &self.gc;
+        if !given(matches, "gc") {
+            args.gc = gc.nightly_days.or(args.gc);
+        }
+        if !given(matches, "keep_nightlies") {
+            args.keep_nightlies = gc.keep_nightlies.or(args.keep_nightlies);
+        }
+        if !given(matches, "keep_betas") {
+            args.keep_betas = gc.keep_betas.or(args.keep_betas);
+        }
+        if !given(matches, "keep_stable_since") {
+            // Checked by validate
+            let since = gc
+                .keep_stable_since
+                .as_ref()
+                .and_then(|version| gc::parse_version(version).ok());
+            args.keep_stable_since = since.or(args.keep_stable_since);
+        }
+        if !given(matches, "pin_file") {
+            args.pin_file = gc.pin_file.clone().or(args.pin_file.take());
+        }
+        if !given(matches, "keep_rustup_versions") {
+            args.keep_rustup_versions = gc.keep_rustup_versions.or(args.keep_rustup_versions);
+        }
+        if !given(matches, "orig_history") {
+            args.orig_history = gc.orig_history.or(args.orig_history);
+        }
+        if !given(matches, "sweep") {
+            args.sweep = gc.sweep.unwrap_or(args.sweep);
+        }
+        if !given(matches, "cache") {
+            args.cache = self.cache.clone().or(args.cache.take());
+        }
+    }
+
+    fn apply_storage(&self, args: &mut StorageArgs, matches: &ArgMatches) {
+        if !given(matches, "storage") {
+            args.storage = self.storage.clone().or(args.storage.take());
+        }
+        let s3 = self.s3.clone().unwrap_or_default();
+        if !given(matches, "s3_endpoint") {
+            args.s3_endpoint = s3.endpoint.or(args.s3_endpoint.take());
+        }
+        if !given(matches, "s3_region") {
+            args.s3_region = s3.region.or(args.s3_region.take());
+        }
+    }
+}
+
+/// Whether an argument was given on the command line, before or after the subcommand
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches.try_contains_id(id).unwrap_or(false)
+        && matches.value_source(id) == Some(ValueSource::CommandLine)
+        || matches
+            .subcommand()
+            .is_some_and(|(_, matches)| given(matches, id))
+}
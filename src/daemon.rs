@@ -0,0 +1,202 @@
+use crate::{cancel, parse_duration, sync, Cli, SyncArgs};
+use anyhow::{anyhow, Error};
+use chrono::Local;
+use clap::Args;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Request, Response, Server, StatusCode};
+
+/// Where the result of the last sync is served
+const STATUS_PATH: &str = "/_status";
+
+#[derive(Args)]
+pub struct DaemonArgs {
+    #[command(flatten)]
+    pub sync: SyncArgs,
+
+    /// Time between the start of two syncs, e.g. 6h
+    #[arg(long, default_value = "6h", value_parser = parse_duration)]
+    interval: Duration,
+
+    /// Delay every sync by a random time of up to this long, e.g. 10m
+    #[arg(long, default_value = "0s", value_parser = parse_duration)]
+    jitter: Duration,
+
+    /// Also serve the mirror on this address, e.g. 0.0.0.0:8000
+    #[arg(long)]
+    listen: Option<String>,
+
+    /// Write the result of the last sync into this JSON file
+    #[arg(long)]
+    status_file: Option<PathBuf>,
+}
+
+#[derive(Serialize, Default, Clone)]
+struct Status {
+    syncing: bool,
+    last_started: Option<String>,
+    last_finished: Option<String>,
+    last_succeeded: Option<bool>,
+    last_errors: Vec<String>,
+    next_sync: Option<String>,
+}
+
+pub fn daemon(clis: &[(Option<String>, Cli)], args: &DaemonArgs) -> Result<(), Error> {
+    let status = Arc::new(Mutex::new(Status::default()));
+
+    if let Some(listen) = &args.listen {
+        let [(_, cli)] = clis else {
+            return Err(anyhow!("Only a single profile can be served"));
+        };
+        serve(listen, PathBuf::from(&cli.mirror), status.clone())?;
+    }
+
+    let random = SystemRandom::new();
+    loop {
+        let started = Local::now();
+        {
+            let mut status = status.lock().unwrap();
+            status.syncing = true;
+            status.last_started = Some(started.to_rfc3339());
+        }
+
+        let mut errors = vec![];
+        for (profile, cli) in clis {
+            if let Some(profile) = profile {
+                println!("Syncing profile {}", profile);
+            }
+            if let Err(err) = sync(cli, cli.sync_args().unwrap()) {
+                if cancel::is_cancel_error(&err) {
+                    return Err(err);
+                }
+                println!("Sync failed: {:#}", err);
+                errors.push(match profile {
+                    Some(profile) => format!("{}: {:#}", profile, err),
+                    None => format!("{:#}", err),
+                });
+            }
+        }
+
+        let mut jitter = [0u8; 8];
+        random
+            .fill(&mut jitter)
+            .map_err(|_| anyhow!("Unable to generate jitter"))?;
+        let jitter = u64::from_le_bytes(jitter) % (args.jitter.as_millis() as u64 + 1);
+        let next = started + args.interval + Duration::from_millis(jitter);
+
+        let status = {
+            let mut status = status.lock().unwrap();
+            status.syncing = false;
+            status.last_finished = Some(Local::now().to_rfc3339());
+            status.last_succeeded = Some(errors.is_empty());
+            status.last_errors = errors;
+            status.next_sync = Some(next.to_rfc3339());
+            status.clone()
+        };
+        if let Some(status_file) = &args.status_file {
+            File::create(status_file)?.write_all(&serde_json::to_vec_pretty(&status)?)?;
+        }
+
+        println!("Next sync at {}", next.to_rfc3339());
+        // Sleep in short steps to notice a signal
+        while let Ok(delay) = (next - Local::now()).to_std() {
+            cancel::check()?;
+            thread::sleep(delay.min(Duration::from_secs(1)));
+        }
+        cancel::check()?;
+    }
+}
+
+fn serve(listen: &str, mirror: PathBuf, status: Arc<Mutex<Status>>) -> Result<(), Error> {
+    let server =
+        Server::http(listen).map_err(|err| anyhow!("Unable to listen on {}: {}", listen, err))?;
+    println!("Serving {} on http://{}", mirror.display(), listen);
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let mirror = mirror.clone();
+            let status = status.clone();
+            thread::spawn(move || {
+                if let Err(err) = respond(request, &mirror, &status) {
+                    println!("Failed to respond: {}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Parse a single `bytes=start-[end]` range against a file length
+fn parse_range(range: &str, length: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start: u64 = start.parse().ok()?;
+    let end = match end {
+        "" => length.checked_sub(1)?,
+        end => end.parse::<u64>().ok()?.min(length.checked_sub(1)?),
+    };
+    (start <= end).then_some((start, end))
+}
+
+fn respond(request: Request, mirror: &Path, status: &Mutex<Status>) -> io::Result<()> {
+    let path = request.url().split(['?', '#']).next().unwrap_or("");
+    let path = path.replace("%20", " ");
+
+    if path == STATUS_PATH {
+        let body = serde_json::to_vec_pretty(&*status.lock().unwrap())?;
+        let response =
+            Response::from_data(body).with_header(header("Content-Type", "application/json"));
+        return request.respond(response);
+    }
+
+    let relative = Path::new(path.trim_start_matches('/'));
+    let file = mirror.join(relative);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        || !file.is_file()
+    {
+        return request.respond(Response::empty(404));
+    }
+
+    let mut file = File::open(file)?;
+    let length = file.metadata()?.len();
+    let range = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Range"))
+        .and_then(|header| parse_range(header.value.as_str(), length));
+
+    let mut headers = vec![
+        header("Content-Type", "application/octet-stream"),
+        header("Accept-Ranges", "bytes"),
+    ];
+    let (code, start, end) = match range {
+        Some((start, end)) => {
+            headers.push(header(
+                "Content-Range",
+                &format!("bytes {}-{}/{}", start, end, length),
+            ));
+            (206, start, end + 1)
+        }
+        None => (200, 0, length),
+    };
+    file.seek(SeekFrom::Start(start))?;
+    let response = Response::new(
+        StatusCode(code),
+        headers,
+        file.take(end - start),
+        Some((end - start) as usize),
+        None,
+    );
+    request.respond(response)
+}
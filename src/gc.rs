@@ -0,0 +1,1095 @@
+use crate::lock::{MirrorLock, LOCK_NAME};
+use crate::storage::{self, Storage};
+use crate::{
+    cancel, cas, history, manifest_artifacts, retention, Cli, ProfileArgs, RetentionArgs,
+    StorageArgs,
+};
+use anyhow::{anyhow, Context, Error};
+use chrono::NaiveDate;
+use clap::Args;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{metadata, read_dir, read_to_string, remove_file};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+#[derive(Args)]
+pub struct GcArgs {
+    #[command(flatten)]
+    pub profiles: ProfileArgs,
+
+    #[command(flatten)]
+    pub retention: RetentionArgs,
+
+    #[command(flatten)]
+    pub storage: StorageArgs,
+
+    /// Only report what would be deleted
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the report of a dry run as JSON
+    #[arg(long, requires = "dry_run")]
+    json: bool,
+
+    /// Channels synced by name, e.g. 1.65.0, whose versioned heads are kept
+    /// as a sync with the same --channels keeps them
+    #[arg(short, long, value_delimiter = ',')]
+    pub channels: Vec<String>,
+}
+
+/// Files and directories a garbage collection pass deletes, relative to the
+/// mirror root
+#[derive(Default)]
+pub struct GcPlan {
+    /// Files deleted together with their checksum
+    pub files: Vec<String>,
+    /// Dated directories with no useful file left, and old rustup archives
+    pub dirs: Vec<String>,
+    /// Objects of the content-addressed store no kept file refers to
+    pub objects: Vec<String>,
+    /// Entries that are left alone, as they are not known
+    pub unknown: Vec<String>,
+    /// Old copies of upstream manifests in the orig directory
+    pub history: Vec<PathBuf>,
+    /// Files nothing kept refers to, found by a sweep
+    pub orphans: Vec<String>,
+    /// Download cache to prune once the mirror files are deleted
+    pub cache: Option<PathBuf>,
+}
+
+/// What a path below dist/ is to garbage collection
+enum Entry<'a> {
+    /// A file of a dated directory, e.g. dist/2024-01-01/rustc-nightly-...
+    Dated {
+        dir: &'a str,
+        date: NaiveDate,
+        file: &'a str,
+    },
+    /// A channel manifest or its checksum directly in dist/
+    Manifest,
+    /// Anything else, by the file or directory directly in dist/
+    Unknown(&'a str),
+}
+
+fn classify(path: &str) -> Entry<'_> {
+    let rest = &path["dist/".len()..];
+    match rest.split_once('/') {
+        Some((dir, file)) => {
+            let dir_path = &path[.."dist/".len() + dir.len()];
+            match NaiveDate::parse_from_str(dir, "%Y-%m-%d") {
+                Ok(date) => Entry::Dated {
+                    dir: dir_path,
+                    date,
+                    file,
+                },
+                Err(_) => Entry::Unknown(dir_path),
+            }
+        }
+        None if rest.starts_with("channel-rust-")
+            && (rest.ends_with(".toml") || rest.ends_with(".toml.sha256")) =>
+        {
+            Entry::Manifest
+        }
+        None => Entry::Unknown(path),
+    }
+}
+
+/// Version of a stable release, major, minor and patch
+pub type Version = (u64, u64, u64);
+
+/// How long a cached file is kept even if no mirror links to it
+#[cfg(unix)]
+const CACHE_GRACE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// What garbage collection keeps besides the files of published channels
+#[derive(Default)]
+pub struct Retention {
+    /// Keep nightly builds of this date and later
+    pub nightly_cutoff: Option<NaiveDate>,
+    /// Keep the last this many nightly builds
+    pub keep_nightlies: Option<usize>,
+    /// Keep the last this many beta builds
+    pub keep_betas: Option<usize>,
+    /// Keep stable releases since this version, and delete the versioned
+    /// channel manifests of older ones
+    pub stable_since: Option<Version>,
+    /// Channels mirrored by name, e.g. 1.65.0, kept whatever their version
+    pub channels: Vec<String>,
+    /// Manifests of pinned toolchains
+    pub pinned: Vec<String>,
+    /// Keep the current and this many previous versions of rustup-init
+    pub keep_rustup_versions: Option<usize>,
+    /// Version of rustup about to be published, the current one instead of
+    /// the one the mirror publishes
+    pub rustup_version: Option<String>,
+    /// Orig directory and how many copies of each upstream manifest its
+    /// history keeps
+    pub orig_history: Option<(PathBuf, usize)>,
+    /// Also delete every file that no kept manifest or rustup release refers to
+    pub sweep: bool,
+    /// Download cache, pruned of files no mirror links to any more
+    pub cache: Option<PathBuf>,
+}
+
+/// Parse a version such as 1.70 or 1.79.0
+pub fn parse_version(version: &str) -> Result<Version, Error> {
+    let parts = version
+        .split('.')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>();
+    match parts.as_deref() {
+        Some(&[major, minor]) => Ok((major, minor, 0)),
+        Some(&[major, minor, patch]) => Ok((major, minor, patch)),
+        _ => Err(anyhow!("Invalid version {}, expected e.g. 1.70.0", version)),
+    }
+}
+
+/// Whether a versioned channel, e.g. 1.79.0 or 1.79, is a release before `since`
+fn released_before(channel: &str, since: Version) -> bool {
+    match parse_version(channel) {
+        Ok(version) if channel.split('.').count() == 2 => {
+            (version.0, version.1) < (since.0, since.1)
+        }
+        Ok(version) => version < since,
+        Err(_) => false,
+    }
+}
+
+/// Read the toolchains of a pin file, one per line, e.g. nightly-2024-01-04 or
+/// 1.79.0, and return the manifests they are installed from
+pub fn read_pins(path: &Path) -> Result<Vec<String>, Error> {
+    let data = read_to_string(path)
+        .with_context(|| format!("Unable to read pin file {}", path.display()))?;
+    let mut pinned = vec![];
+    for line in data.lines() {
+        let toolchain = line.trim();
+        if toolchain.is_empty() || toolchain.starts_with('#') {
+            continue;
+        }
+        if parse_version(toolchain).is_ok() {
+            pinned.push(format!("dist/channel-rust-{}.toml", toolchain));
+            continue;
+        }
+        let date_start = toolchain.len().saturating_sub(10);
+        let channel = toolchain
+            .get(..date_start)
+            .and_then(|channel| channel.strip_suffix('-'))
+            .filter(|channel| !channel.is_empty());
+        let date = toolchain
+            .get(date_start..)
+            .filter(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok());
+        match channel.zip(date) {
+            Some((channel, date)) => {
+                pinned.push(format!("dist/{}/channel-rust-{}.toml", date, channel))
+            }
+            None => {
+                return Err(anyhow!(
+                    "Invalid toolchain {} in pin file {}, expected e.g. nightly-2024-01-04 or 1.79.0",
+                    toolchain,
+                    path.display()
+                ));
+            }
+        }
+    }
+    Ok(pinned)
+}
+
+/// Find nightly builds and stable/beta builds that neither `referenced` nor
+/// any manifest kept by `retention` refers to. Published channel manifests
+/// are kept, except those of `replaced_channels`, which are about to be
+/// replaced, and versioned ones older than `retention.stable_since`.
+pub fn plan(
+    storage: &dyn Storage,
+    referenced: &HashSet<String>,
+    replaced_channels: &[String],
+    retention: &Retention,
+) -> Result<GcPlan, Error> {
+    let mut plan = GcPlan {
+        cache: retention.cache.clone(),
+        ..GcPlan::default()
+    };
+    // Directories that cannot be read or are empty stay unknown, even to a sweep
+    let mut unlisted = BTreeSet::new();
+    let paths = list_entries(storage, "dist", None, &mut unlisted)?;
+
+    // Channels synced earlier stay intact when only some are synced now
+    let mut roots = vec![];
+    for path in &paths {
+        let Some(channel) = path
+            .strip_prefix("dist/channel-rust-")
+            .and_then(|name| name.strip_suffix(".toml"))
+        else {
+            continue;
+        };
+        if replaced_channels.iter().any(|replaced| replaced == channel) {
+            continue;
+        }
+        if retention
+            .stable_since
+            .is_some_and(|since| released_before(channel, since))
+            && !retention.channels.iter().any(|kept| kept == channel)
+            && !referenced.contains(path)
+            && !retention.pinned.contains(path)
+        {
+            plan.files.push(path.clone());
+            continue;
+        }
+        roots.push(path.clone());
+    }
+
+    // The last builds of a channel, by their dated manifests
+    for (channel, keep) in [
+        ("nightly", retention.keep_nightlies),
+        ("beta", retention.keep_betas),
+    ] {
+        let Some(keep) = keep else {
+            continue;
+        };
+        let name = format!("channel-rust-{}.toml", channel);
+        let mut dated: Vec<&String> = paths
+            .iter()
+            .filter(|path| matches!(classify(path), Entry::Dated { file, .. } if file == name))
+            .collect();
+        // Dates sort like strings, latest first
+        dated.sort_by(|a, b| b.cmp(a));
+        roots.extend(dated.into_iter().take(keep).cloned());
+    }
+
+    for pinned in &retention.pinned {
+        if paths.contains(pinned) {
+            roots.push(pinned.clone());
+        } else {
+            eprintln!("Warning: pinned manifest /{} is not in the mirror", pinned);
+        }
+    }
+
+    let mut referenced = referenced.clone();
+    for root in roots {
+        let Some(data) = storage.get(&root)? else {
+            continue;
+        };
+        let value: Value = toml::from_str(&String::from_utf8_lossy(&data))
+            .with_context(|| format!("Invalid manifest /{}", root))?;
+        referenced.extend(
+            manifest_artifacts(&value, None)
+                .with_context(|| format!("Invalid manifest /{}", root))?
+                .into_iter()
+                .map(|(path, _)| path),
+        );
+        let channel = root
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.strip_prefix("channel-rust-"))
+            .and_then(|name| name.strip_suffix(".toml"));
+        if let (Some(channel), Some(date)) = (channel, value.get("date").and_then(Value::as_str)) {
+            referenced.insert(format!("dist/{}/channel-rust-{}.toml", date, channel));
+        }
+        referenced.insert(root);
+    }
+
+    // Files of each dated directory, manifests directly in dist/ are kept and
+    // anything unknown is left alone
+    let mut date_dirs: BTreeMap<&str, (NaiveDate, Vec<String>)> = BTreeMap::new();
+    let mut unknown = unlisted.clone();
+    for path in &paths {
+        match classify(path) {
+            Entry::Dated { dir, date, .. } => {
+                date_dirs
+                    .entry(dir)
+                    .or_insert((date, vec![]))
+                    .1
+                    .push(path.clone());
+            }
+            Entry::Manifest => {}
+            Entry::Unknown(entry) => {
+                unknown.insert(entry.to_string());
+            }
+        }
+    }
+
+    // Checksums of the kept files, the objects they refer to stay
+    let store = storage
+        .local_path(cas::CAS_DIR)
+        .filter(|store| store.exists());
+    let mut live = HashSet::new();
+    // Channel manifests that stay, a sweep keeps what they refer to
+    let mut kept_manifests = vec![];
+
+    for (date_dir, (date, files)) in date_dirs {
+        let clear_nightly = match retention.nightly_cutoff {
+            Some(nightly_cutoff) => date < nightly_cutoff,
+            // Only the last nightly builds are kept
+            None => retention.keep_nightlies.is_some(),
+        };
+
+        // Is there anyone left?
+        let mut perserve_dir = false;
+
+        for file in files {
+            let fname = &file[date_dir.len() + 1..];
+            if fname.ends_with(".sha256") {
+                // Is an hash, will be deleted alongside the hashed file
+                continue;
+            }
+
+            // Filter referenced artifacts and manifests
+            let to_be_deleted = if referenced.contains(&file) {
+                false
+            } else if fname.contains("nightly") {
+                // Is nightly artifact or manifest
+                clear_nightly
+            } else {
+                // Is stable/beta artifact or manifest, delete by default
+                true
+            };
+
+            if to_be_deleted {
+                plan.files.push(file);
+            } else {
+                perserve_dir = true;
+                if fname.starts_with("channel-rust-") && fname.ends_with(".toml") {
+                    kept_manifests.push(file.clone());
+                }
+                if store.is_some() {
+                    if let Some(sha256) = storage.get(&format!("{}.sha256", file))? {
+                        live.insert(String::from_utf8_lossy(&sha256).trim().to_string());
+                    }
+                    live.extend(
+                        storage
+                            .local_path(&file)
+                            .and_then(|file| cas::symlink_target(&file)),
+                    );
+                }
+            }
+        }
+
+        if !perserve_dir {
+            plan.dirs.push(date_dir.to_string());
+        }
+    }
+
+    if store.is_some() {
+        for object in storage.list(cas::CAS_DIR)? {
+            let sha256 = object.rsplit('/').next().unwrap_or_default();
+            if !live.contains(sha256) {
+                plan.objects.push(object);
+            }
+        }
+    }
+
+    if let Some(keep) = retention.keep_rustup_versions {
+        plan.dirs
+            .extend(old_rustup_archives(storage, keep, retention, &mut unknown)?);
+    }
+
+    if let Some((orig, keep)) = &retention.orig_history {
+        plan.history = history::old(orig, *keep)?;
+    }
+
+    if retention.sweep {
+        kept_manifests.extend(
+            paths
+                .iter()
+                .filter(|path| matches!(classify(path), Entry::Manifest))
+                .filter(|path| path.ends_with(".toml") && !plan.files.contains(path))
+                .cloned(),
+        );
+        plan.orphans = orphans(storage, &paths, &kept_manifests, &referenced, &plan)?;
+        // Swept like any other file
+        unknown.retain(|entry| unlisted.contains(entry));
+    }
+
+    for entry in &unknown {
+        eprintln!("Warning: unknown entry /{} left alone", entry);
+    }
+    plan.unknown = unknown.into_iter().collect();
+    Ok(plan)
+}
+
+/// Files of the mirror that no kept manifest or rustup release refers to,
+/// such as partial downloads, and checksums of files that are gone. Files in
+/// `referenced` are needed by manifests that are not published yet
+fn orphans(
+    storage: &dyn Storage,
+    dist_paths: &[String],
+    manifests: &[String],
+    referenced: &HashSet<String>,
+    plan: &GcPlan,
+) -> Result<Vec<String>, Error> {
+    let mut reachable = referenced.clone();
+    for manifest in manifests {
+        reachable.insert(manifest.clone());
+        let Some(data) = storage.get(manifest)? else {
+            continue;
+        };
+        let value: Value = toml::from_str(&String::from_utf8_lossy(&data))
+            .with_context(|| format!("Invalid manifest /{}", manifest))?;
+        reachable.extend(
+            manifest_artifacts(&value, None)
+                .with_context(|| format!("Invalid manifest /{}", manifest))?
+                .into_iter()
+                .map(|(path, _)| path),
+        );
+    }
+
+    let mut paths = dist_paths.to_vec();
+    paths.extend(list_entries(
+        storage,
+        "",
+        Some("dist"),
+        &mut BTreeSet::new(),
+    )?);
+    let existing: HashSet<&str> = paths.iter().map(String::as_str).collect();
+    let deleted: HashSet<&str> = plan.files.iter().map(String::as_str).collect();
+    let mut orphans = vec![];
+    for path in &paths {
+        let base = path.strip_suffix(".sha256").unwrap_or(path);
+        // Deleted anyway, or not a file of the mirror
+        if deleted.contains(base)
+            || plan
+                .dirs
+                .iter()
+                .any(|dir| path.starts_with(&format!("{}/", dir)))
+            || path.starts_with(".cas/")
+            || path == LOCK_NAME
+        {
+            continue;
+        }
+        let needed = if base != path {
+            existing.contains(base) && is_reachable(base, &reachable)
+        } else {
+            is_reachable(path, &reachable)
+        };
+        if !needed {
+            orphans.push(path.clone());
+        }
+    }
+    Ok(orphans)
+}
+
+/// Paths of all files below a directory but the entry `except`, listed entry
+/// by entry so that a directory that cannot be read, such as a lost+found
+/// owned by root, or an empty one ends up in `unlisted` instead
+fn list_entries(
+    storage: &dyn Storage,
+    dir: &str,
+    except: Option<&str>,
+    unlisted: &mut BTreeSet<String>,
+) -> Result<Vec<String>, Error> {
+    let prefix = match dir {
+        "" => String::new(),
+        dir => format!("{}/", dir),
+    };
+    let except = except.map(|except| format!("{}{}", prefix, except));
+    let Some(local) = storage.local_path(dir) else {
+        // Object stores have neither directories nor permissions of their own
+        return Ok(storage
+            .list(dir)?
+            .into_iter()
+            .filter(|path| {
+                except
+                    .as_ref()
+                    .is_none_or(|except| !path.starts_with(&format!("{}/", except)))
+            })
+            .collect());
+    };
+    let entries = match read_dir(&local) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Unable to list {}", local.display()));
+        }
+    };
+    let mut paths = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if except.as_ref() == Some(&path) {
+            continue;
+        }
+        if !entry.file_type()?.is_dir() {
+            paths.push(path);
+            continue;
+        }
+        match storage.list(&path) {
+            Ok(files) if files.is_empty() => {
+                unlisted.insert(path);
+            }
+            Ok(files) => paths.extend(files),
+            Err(err) => {
+                eprintln!("Warning: unable to read /{}: {}", path, err);
+                unlisted.insert(path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Whether a kept manifest refers to a file, or it is part of a rustup release
+fn is_reachable(path: &str, reachable: &HashSet<String>) -> bool {
+    let is_rustup_init = |name: &str| name == "rustup-init" || name == "rustup-init.exe";
+    match path.split('/').collect::<Vec<_>>().as_slice() {
+        _ if reachable.contains(path) => true,
+        ["rustup", "release-stable.toml"] => true,
+        ["rustup", "dist", _, name] => is_rustup_init(name),
+        ["rustup", "archive", version, _, name] => {
+            parse_version(version).is_ok() && is_rustup_init(name)
+        }
+        _ => false,
+    }
+}
+
+/// Directories of rustup versions in rustup/archive, but the latest `keep` + 1
+/// and the current one
+fn old_rustup_archives(
+    storage: &dyn Storage,
+    keep: usize,
+    retention: &Retention,
+    unknown: &mut BTreeSet<String>,
+) -> Result<Vec<String>, Error> {
+    let current = match &retention.rustup_version {
+        Some(version) => Some(version.clone()),
+        None => storage
+            .get("rustup/release-stable.toml")?
+            .and_then(|data| toml::from_str::<Value>(&String::from_utf8_lossy(&data)).ok())
+            .and_then(|value| Some(value.get("version")?.as_str()?.to_string())),
+    };
+
+    let mut versions = BTreeMap::new();
+    for path in storage.list("rustup/archive")? {
+        let Some((name, _)) = path["rustup/archive/".len()..].split_once('/') else {
+            unknown.insert(path);
+            continue;
+        };
+        match parse_version(name) {
+            Ok(version) => {
+                versions.insert(version, name.to_string());
+            }
+            Err(_) => {
+                unknown.insert(format!("rustup/archive/{}", name));
+            }
+        }
+    }
+
+    Ok(versions
+        .into_values()
+        .rev()
+        .skip(keep + 1)
+        .filter(|name| current.as_ref() != Some(name))
+        .map(|name| format!("rustup/archive/{}", name))
+        .collect())
+}
+
+impl GcPlan {
+    pub fn apply(&self, storage: &dyn Storage) -> Result<(), Error> {
+        for file in &self.files {
+            // Delete artifact / manifest and its corresponding hash
+            println!("Deleting file /{}[.sha256]", file);
+            storage.delete(file)?;
+            // Ignore error if the hash is not deleted (e.g. there is no hash present)
+            let _ = storage.delete(&format!("{}.sha256", file));
+        }
+        for dir in &self.dirs {
+            if dir.starts_with("rustup/") {
+                println!("Deleting old rustup archive /{}", dir);
+            } else {
+                println!(
+                    "No useful file left in dir /{}, removing the entire directory.",
+                    dir
+                );
+            }
+            storage.delete_dir(dir)?;
+        }
+        for object in &self.objects {
+            println!("Deleting unreferenced object /{}", object);
+            storage.delete(object)?;
+        }
+        for file in &self.history {
+            println!("Deleting old upstream manifest {}", file.display());
+            remove_file(file)?;
+        }
+        for orphan in &self.orphans {
+            println!("Deleting orphan file /{}", orphan);
+            storage.delete(orphan)?;
+        }
+        if let Some(cache) = &self.cache {
+            for file in unlinked_cache_files(cache)? {
+                println!("Deleting cached file {}", file.display());
+                remove_file(file)?;
+            }
+        }
+        if !self.unknown.is_empty() {
+            println!("Left {} unknown entries alone", self.unknown.len());
+        }
+        Ok(())
+    }
+
+    /// Print what `apply` would delete
+    pub fn report(&self) {
+        for file in &self.files {
+            println!("Would delete file /{}[.sha256]", file);
+        }
+        for dir in &self.dirs {
+            println!("Would remove directory /{}", dir);
+        }
+        for object in &self.objects {
+            println!("Would delete unreferenced object /{}", object);
+        }
+        for file in &self.history {
+            println!("Would delete old upstream manifest {}", file.display());
+        }
+        for orphan in &self.orphans {
+            println!("Would delete orphan file /{}", orphan);
+        }
+        for entry in &self.unknown {
+            println!("Would leave unknown entry /{} alone", entry);
+        }
+    }
+}
+
+/// Files of a download cache that no mirror links to any more. Mirrors
+/// hardlink cached files, so a link count of one means only the cache holds it
+#[cfg(unix)]
+fn unlinked_cache_files(cache: &Path) -> Result<Vec<PathBuf>, Error> {
+    use std::fs::read_dir;
+    use std::os::unix::fs::MetadataExt;
+    let mut files = vec![];
+    let mut dirs = vec![cache.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            // Another profile may have just downloaded it and not linked it yet
+            let recent = metadata
+                .modified()?
+                .elapsed()
+                .is_ok_and(|age| age < CACHE_GRACE);
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if metadata.is_file() && metadata.nlink() == 1 && !recent {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(not(unix))]
+fn unlinked_cache_files(_cache: &Path) -> Result<Vec<PathBuf>, Error> {
+    // Without link counts, any cached file may still be in use
+    Ok(vec![])
+}
+
+/// Deletions of a plan for one channel and date
+#[derive(Serialize)]
+struct Group {
+    channel: String,
+    date: String,
+    files: usize,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    files: &'a [String],
+    dirs: &'a [String],
+    objects: &'a [String],
+    unknown: &'a [String],
+    history: &'a [PathBuf],
+    orphans: &'a [String],
+    groups: Vec<Group>,
+    bytes: u64,
+}
+
+/// Date and channel of a deleted file, for reports. Versioned channels are
+/// stable releases, and files of unknown entries are other files.
+fn group_of(path: &str) -> (String, String) {
+    if path.starts_with("rustup/") {
+        return ("-".to_string(), "rustup".to_string());
+    }
+    if path.starts_with(".cas/") {
+        return ("-".to_string(), "store".to_string());
+    }
+    if !path.starts_with("dist/") {
+        return ("-".to_string(), "other".to_string());
+    }
+    let (date, name) = match classify(path) {
+        Entry::Dated { date, file, .. } => (date.to_string(), file),
+        Entry::Manifest => ("-".to_string(), &path["dist/".len()..]),
+        Entry::Unknown(_) => return ("-".to_string(), "other".to_string()),
+    };
+    let manifest_channel = name
+        .strip_prefix("channel-rust-")
+        .and_then(|name| name.strip_suffix(".toml"))
+        .filter(|channel| parse_version(channel).is_err());
+    let channel = if let Some(channel) = manifest_channel {
+        channel
+    } else if name.contains("nightly") {
+        "nightly"
+    } else if name.contains("beta") {
+        "beta"
+    } else {
+        "stable"
+    };
+    (date.to_string(), channel.to_string())
+}
+
+impl GcPlan {
+    /// Files and reclaimable bytes by channel and date
+    fn groups(&self, storage: &dyn Storage) -> Result<Vec<Group>, Error> {
+        let mut groups: BTreeMap<(String, String), (usize, u64)> = BTreeMap::new();
+        for file in &self.files {
+            let size = storage.file_size(file)?.unwrap_or(0)
+                + storage.file_size(&format!("{}.sha256", file))?.unwrap_or(0);
+            let group = groups.entry(group_of(file)).or_default();
+            group.0 += 1;
+            group.1 += size;
+        }
+        for object in self.objects.iter().chain(&self.orphans) {
+            let group = groups.entry(group_of(object)).or_default();
+            group.0 += 1;
+            group.1 += storage.file_size(object)?.unwrap_or(0);
+        }
+        for file in &self.history {
+            let group = groups
+                .entry(("-".to_string(), "history".to_string()))
+                .or_default();
+            group.0 += 1;
+            group.1 += metadata(file)?.len();
+        }
+        // Files of dated directories are counted above
+        for dir in self.dirs.iter().filter(|dir| dir.starts_with("rustup/")) {
+            for file in storage.list(dir)? {
+                let group = groups.entry(group_of(&file)).or_default();
+                group.0 += 1;
+                group.1 += storage.file_size(&file)?.unwrap_or(0);
+            }
+        }
+        Ok(groups
+            .into_iter()
+            .map(|((date, channel), (files, bytes))| Group {
+                channel,
+                date,
+                files,
+                bytes,
+            })
+            .collect())
+    }
+}
+
+/// Collect garbage on its own, keeping the files of all published channels
+pub fn gc(cli: &Cli, args: &GcArgs) -> Result<(), Error> {
+    let _lock = if args.dry_run {
+        None
+    } else {
+        Some(MirrorLock::acquire(&cli.mirror, args.storage.wait)?)
+    };
+    let storage = storage::open(&cli.mirror, &args.storage)?;
+    let retention = retention(cli, &args.retention, &args.channels)?;
+    let plan = plan(&*storage, &HashSet::new(), &[], &retention)?;
+    let groups = plan.groups(&*storage)?;
+    let bytes = groups.iter().map(|group| group.bytes).sum();
+
+    if args.json {
+        let report = Report {
+            files: &plan.files,
+            dirs: &plan.dirs,
+            objects: &plan.objects,
+            unknown: &plan.unknown,
+            history: &plan.history,
+            orphans: &plan.orphans,
+            groups,
+            bytes,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    if args.dry_run {
+        plan.report();
+    }
+    println!(
+        "{:<12} {:<20} {:>8} {:>12}",
+        "Date", "Channel", "Files", "Size"
+    );
+    for group in &groups {
+        println!(
+            "{:<12} {:<20} {:>8} {:>12}",
+            group.date,
+            group.channel,
+            group.files,
+            HumanBytes(group.bytes).to_string()
+        );
+    }
+    println!(
+        "{} files, {} directories, {} store objects and {} orphan files, {} reclaimable, {} unknown entries left alone",
+        plan.files.len(),
+        plan.dirs.len(),
+        plan.objects.len(),
+        plan.orphans.len(),
+        HumanBytes(bytes),
+        plan.unknown.len()
+    );
+    if args.dry_run {
+        return Ok(());
+    }
+
+    cancel::check()?;
+    plan.apply(&*storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FsStorage;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    /// A mirror in a temporary directory, removed when dropped
+    struct TestMirror {
+        root: PathBuf,
+        storage: FsStorage,
+    }
+
+    impl TestMirror {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "rustup-mirror-gc-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = remove_dir_all(&root);
+            create_dir_all(&root).unwrap();
+            TestMirror {
+                storage: FsStorage::new(&root),
+                root,
+            }
+        }
+
+        /// A file and its checksum
+        fn file(&self, path: &str) {
+            self.storage.put(path, path.as_bytes()).unwrap();
+            self.storage
+                .put(&format!("{}.sha256", path), b"0000")
+                .unwrap();
+        }
+
+        /// A dated channel manifest referring to `files` of its date, which
+        /// are created too, published as the channel head if `head` is set
+        fn channel(&self, channel: &str, date: &str, files: &[&str], head: bool) {
+            let mut manifest = format!("manifest-version = \"2\"\ndate = \"{}\"\n", date);
+            for (idx, file) in files.iter().enumerate() {
+                let path = format!("dist/{}/{}", date, file);
+                manifest.push_str(&format!(
+                    "[pkg.pkg{}.target.x86_64-unknown-linux-gnu]\navailable = true\nurl = \"https://static.rust-lang.org/{}\"\nhash = \"0000\"\n",
+                    idx, path
+                ));
+                self.file(&path);
+            }
+            let dated = format!("dist/{}/channel-rust-{}.toml", date, channel);
+            self.storage.put(&dated, manifest.as_bytes()).unwrap();
+            if head {
+                let head = format!("dist/channel-rust-{}.toml", channel);
+                self.storage.put(&head, manifest.as_bytes()).unwrap();
+            }
+        }
+
+        fn plan(&self, referenced: &[&str], replaced: &[&str], retention: &Retention) -> GcPlan {
+            let referenced = referenced.iter().map(|path| path.to_string()).collect();
+            let replaced: Vec<String> =
+                replaced.iter().map(|channel| channel.to_string()).collect();
+            plan(&self.storage, &referenced, &replaced, retention).unwrap()
+        }
+    }
+
+    impl Drop for TestMirror {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn partial_sync_keeps_other_channels() {
+        let mirror = TestMirror::new("partial");
+        mirror.channel("stable", "2024-01-01", &["rustc-stable.tar.gz"], true);
+        mirror.channel("nightly", "2024-01-03", &["rustc-nightly.tar.gz"], true);
+        mirror.file("dist/2024-02-01/rustc-stable.tar.gz");
+
+        // A dry run syncing stable only, whose new manifest is not published
+        let plan = mirror.plan(
+            &["dist/2024-02-01/rustc-stable.tar.gz"],
+            &["stable"],
+            &Retention::default(),
+        );
+        assert!(plan
+            .files
+            .contains(&"dist/2024-01-01/rustc-stable.tar.gz".to_string()));
+        assert!(plan.dirs.contains(&"dist/2024-01-01".to_string()));
+        assert!(!plan.files.iter().any(|file| file.contains("2024-01-03")));
+        assert!(!plan.files.iter().any(|file| file.contains("2024-02-01")));
+    }
+
+    #[test]
+    fn unknown_entries_are_left_alone() {
+        let mirror = TestMirror::new("unknown");
+        mirror.channel("stable", "2024-01-01", &["rustc-stable.tar.gz"], true);
+        mirror.file("dist/lost+found/file");
+        mirror.file("dist/notes.txt");
+        create_dir_all(mirror.root.join("dist/empty")).unwrap();
+
+        let plan = mirror.plan(&[], &[], &Retention::default());
+        assert_eq!(
+            plan.unknown,
+            [
+                "dist/empty",
+                "dist/lost+found",
+                "dist/notes.txt",
+                "dist/notes.txt.sha256"
+            ]
+        );
+        assert!(plan.files.is_empty());
+        assert!(plan.dirs.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_last_nightlies_and_betas() {
+        let mirror = TestMirror::new("keep");
+        for (date, head) in [
+            ("2024-01-01", false),
+            ("2024-01-02", false),
+            ("2024-01-03", true),
+        ] {
+            mirror.channel("nightly", date, &["rustc-nightly.tar.gz"], head);
+            mirror.channel("beta", date, &["rustc-beta.tar.gz"], head);
+        }
+
+        let retention = Retention {
+            keep_nightlies: Some(2),
+            keep_betas: Some(1),
+            ..Retention::default()
+        };
+        let mut files = mirror.plan(&[], &[], &retention).files;
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "dist/2024-01-01/channel-rust-beta.toml",
+                "dist/2024-01-01/channel-rust-nightly.toml",
+                "dist/2024-01-01/rustc-beta.tar.gz",
+                "dist/2024-01-01/rustc-nightly.tar.gz",
+                "dist/2024-01-02/channel-rust-beta.toml",
+                "dist/2024-01-02/rustc-beta.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn pinned_manifests_keep_their_files() {
+        let mirror = TestMirror::new("pinned");
+        mirror.channel("stable", "2024-01-01", &["rustc-stable.tar.gz"], false);
+        mirror.channel("stable", "2024-02-01", &["rustc-stable.tar.gz"], true);
+        mirror.channel("nightly", "2024-01-04", &["rustc-nightly.tar.gz"], false);
+
+        let pins = mirror.root.join("pins.txt");
+        write(
+            &pins,
+            "# toolchains in use\nstable-2024-01-01\n\nnightly-2024-01-04\n",
+        )
+        .unwrap();
+        let retention = Retention {
+            pinned: read_pins(&pins).unwrap(),
+            keep_nightlies: Some(0),
+            ..Retention::default()
+        };
+        let plan = mirror.plan(&[], &[], &retention);
+        assert!(plan.files.is_empty(), "{:?}", plan.files);
+        assert!(plan.dirs.is_empty());
+    }
+
+    #[test]
+    fn reads_pins() {
+        let mirror = TestMirror::new("pins");
+        let pins = mirror.root.join("pins.txt");
+        write(&pins, "nightly-2024-01-04\n  1.79.0  \n# 1.70.0\n").unwrap();
+        assert_eq!(
+            read_pins(&pins).unwrap(),
+            [
+                "dist/2024-01-04/channel-rust-nightly.toml",
+                "dist/channel-rust-1.79.0.toml"
+            ]
+        );
+        write(&pins, "nightly\n").unwrap();
+        assert!(read_pins(&pins).is_err());
+    }
+
+    #[test]
+    fn sweep_keeps_checksums_of_kept_files() {
+        let mirror = TestMirror::new("sweep");
+        mirror.channel("stable", "2024-01-01", &["rustc-stable.tar.gz"], true);
+        mirror.file("rustup/release-stable.toml");
+        mirror
+            .storage
+            .put("dist/channel-rust-stable.toml.part", b"")
+            .unwrap();
+        mirror
+            .storage
+            .put("dist/2024-01-01/gone.tar.gz.sha256", b"0000")
+            .unwrap();
+        // Downloaded by a sync whose manifest is not published yet
+        mirror.file("dist/2024-02-01/rustc-stable.tar.gz");
+
+        let retention = Retention {
+            sweep: true,
+            ..Retention::default()
+        };
+        let mut plan = mirror.plan(&["dist/2024-02-01/rustc-stable.tar.gz"], &[], &retention);
+        plan.orphans.sort();
+        assert_eq!(
+            plan.orphans,
+            [
+                "dist/2024-01-01/gone.tar.gz.sha256",
+                "dist/channel-rust-stable.toml.part",
+            ]
+        );
+        assert!(plan.files.is_empty());
+    }
+
+    #[test]
+    fn versioned_channels_before_stable_since() {
+        let mirror = TestMirror::new("versioned");
+        mirror.channel("1.65.0", "2022-11-03", &["rustc-1.65.0.tar.gz"], true);
+        mirror.channel("1.79.0", "2024-06-13", &["rustc-1.79.0.tar.gz"], true);
+
+        let mut retention = Retention {
+            stable_since: Some((1, 70, 0)),
+            ..Retention::default()
+        };
+        let plan = mirror.plan(&[], &[], &retention);
+        assert!(plan
+            .files
+            .contains(&"dist/channel-rust-1.65.0.toml".to_string()));
+        assert!(plan.dirs.contains(&"dist/2022-11-03".to_string()));
+        assert!(!plan.files.iter().any(|file| file.contains("1.79.0")));
+
+        // Synced by name
+        retention.channels = vec!["1.65.0".to_string()];
+        let plan = mirror.plan(&[], &[], &retention);
+        assert!(plan.files.is_empty(), "{:?}", plan.files);
+    }
+
+    #[test]
+    fn releases_before_a_version() {
+        assert!(released_before("1.65.0", (1, 70, 0)));
+        assert!(released_before("1.69", (1, 70, 0)));
+        assert!(!released_before("1.70", (1, 70, 5)));
+        assert!(!released_before("1.70.0", (1, 70, 0)));
+        assert!(!released_before("stable", (1, 70, 0)));
+        assert!(!released_before("nightly-2024-01-01", (1, 70, 0)));
+    }
+}
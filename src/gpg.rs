@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context, Error};
+use pgp::composed::{Deserializable, DetachedSignature, SignedPublicKey};
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Tag and release signing key of the Rust project, fingerprint
+/// 108F 6620 5EAE B0AA A8DD 5E1C 85AB 96E6 FA1B E5FE
+const RUST_RELEASE_KEY: &str = include_str!("rust-key.gpg.ascii");
+
+/// Load an armored public key, the Rust release key by default
+pub fn load_key(path: Option<&Path>) -> Result<SignedPublicKey, Error> {
+    let (armored, name) = match path {
+        Some(path) => (
+            read_to_string(path)
+                .with_context(|| format!("Unable to read public key {}", path.display()))?,
+            path.display().to_string(),
+        ),
+        None => (RUST_RELEASE_KEY.to_string(), "Rust release key".to_string()),
+    };
+    let (key, _) = SignedPublicKey::from_string(&armored)
+        .with_context(|| format!("Invalid public key {}", name))?;
+    key.verify_bindings()
+        .with_context(|| format!("Invalid public key {}", name))?;
+    Ok(key)
+}
+
+/// Check an armored detached signature of some data, made by the primary key
+/// or one of its subkeys
+pub fn verify(key: &SignedPublicKey, data: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let (signature, _) =
+        DetachedSignature::from_armor_single(signature).context("Invalid signature")?;
+    if signature.verify(key, data).is_ok()
+        || key
+            .public_subkeys
+            .iter()
+            .any(|subkey| signature.verify(subkey, data).is_ok())
+    {
+        return Ok(());
+    }
+    Err(anyhow!("Signature does not match the public key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ed25519 key generated for these tests only
+    const TEST_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatUUlhYJKwYBBAHaRw8BAQdATD98ULwdFQzpJtxxZ3ToAM3QoYO5D/CGMgXA
+IPXpRZ60FFRlc3QgPHRAZXhhbXBsZS5jb20+iJAEExYIADgWIQQ8bEwJJaek1Idz
+SAJzea6JBG503AUCatUUlgIbAwULCQgHAgYVCgkICwIEFgIDAQIeAQIXgAAKCRBz
+ea6JBG503IWGAQD0W72ZJtZxzRwkrwxCl1JbGpGKnr4BFfYLmFbBz42TfwEA74k1
+2H3U9+wxhGbfQNsuDINBISc/7QACs2x7xhiW6Ac=
+=sbRF
+-----END PGP PUBLIC KEY BLOCK-----
+";
+
+    const DATA: &[u8] = b"manifest-version = \"2\"\n";
+
+    /// Detached signature of `DATA` by `TEST_KEY`
+    const SIGNATURE: &[u8] = b"-----BEGIN PGP SIGNATURE-----
+
+iHUEABYIAB0WIQQ8bEwJJaek1IdzSAJzea6JBG503AUCatUaYwAKCRBzea6JBG50
+3ABtAQC2aH5PRK1JfOi0HvlbFp7uNqJoo2k1PcAfOt578B5uKQEAxxyszXGE5D4q
+CdMOIRi+2M6BPgaLaui4mj1AGTrECg4=
+=lY3r
+-----END PGP SIGNATURE-----
+";
+
+    fn test_key() -> SignedPublicKey {
+        let (key, _) = SignedPublicKey::from_string(TEST_KEY).unwrap();
+        key.verify_bindings().unwrap();
+        key
+    }
+
+    #[test]
+    fn valid_signature() {
+        verify(&test_key(), DATA, SIGNATURE).unwrap();
+    }
+
+    #[test]
+    fn tampered_data() {
+        let tampered = b"manifest-version = \"3\"\n";
+        assert!(verify(&test_key(), tampered, SIGNATURE).is_err());
+    }
+
+    #[test]
+    fn rust_release_key() {
+        load_key(None).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::Error;
+use chrono::Utc;
+use std::fs::{create_dir_all, read, read_dir, write};
+use std::path::{Path, PathBuf};
+
+/// Directory of the manifest history inside the orig directory
+pub const HISTORY_DIR: &str = "history";
+
+/// Keep a timestamped copy of an upstream channel manifest and its detached
+/// signature, unless it is the same as the last one kept
+pub fn record(
+    orig: &Path,
+    channel: &str,
+    data: &[u8],
+    signature: Option<&[u8]>,
+) -> Result<(), Error> {
+    let dir = orig
+        .join(HISTORY_DIR)
+        .join(format!("channel-rust-{}", channel));
+    let file = match copies(&dir)?.pop() {
+        Some(last) if read(&last)? == data => last,
+        _ => {
+            create_dir_all(&dir)?;
+            let file = dir.join(format!("{}.toml", Utc::now().format("%Y%m%dT%H%M%SZ")));
+            println!("Keeping upstream manifest as {}", file.display());
+            write(&file, data)?;
+            file
+        }
+    };
+    if let Some(signature) = signature {
+        // Also when the copy was kept before signatures were checked
+        write(signature_path(&file), signature)?;
+    }
+    Ok(())
+}
+
+/// Copies of each channel but the last `keep` ones
+pub fn old(orig: &Path, keep: usize) -> Result<Vec<PathBuf>, Error> {
+    let history = orig.join(HISTORY_DIR);
+    if !history.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut old = vec![];
+    for entry in read_dir(history)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let copies = copies(&entry.path())?;
+            let count = copies.len().saturating_sub(keep);
+            for copy in copies.into_iter().take(count) {
+                let signature = signature_path(&copy);
+                if signature.exists() {
+                    old.push(signature);
+                }
+                old.push(copy);
+            }
+        }
+    }
+    Ok(old)
+}
+
+/// Detached signature kept next to a copy
+fn signature_path(copy: &Path) -> PathBuf {
+    copy.with_extension("toml.asc")
+}
+
+/// Copies of a channel, oldest first
+fn copies(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut copies = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            copies.push(path);
+        }
+    }
+    // Timestamps sort like strings
+    copies.sort();
+    Ok(copies)
+}
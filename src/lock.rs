@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Error};
+use chrono::Local;
+use std::fs::{create_dir_all, read_to_string, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+/// Name of the lock file inside the mirror directory
+pub const LOCK_NAME: &str = ".rustup-mirror.lock";
+
+/// Exclusive advisory lock on a mirror directory, released when dropped.
+///
+/// The lock file records its holder and is emptied on release, so a lock
+/// file with content that can be locked was left behind by a crashed run.
+pub struct MirrorLock {
+    file: File,
+}
+
+impl MirrorLock {
+    pub fn acquire(mirror: &str, wait: bool) -> Result<Self, Error> {
+        create_dir_all(mirror)?;
+        let path = Path::new(mirror).join(LOCK_NAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let holder = read_to_string(&path)
+                    .ok()
+                    .filter(|holder| !holder.trim().is_empty())
+                    .unwrap_or_else(|| "unknown holder".to_string());
+                if !wait {
+                    return Err(anyhow!(
+                        "Mirror {} is locked by another run ({}), use --wait to wait for it",
+                        mirror,
+                        holder.trim()
+                    ));
+                }
+                println!(
+                    "Mirror {} is locked by another run ({}), waiting",
+                    mirror,
+                    holder.trim()
+                );
+                file.lock()?;
+            }
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+
+        let stale = read_to_string(&path)?;
+        if !stale.trim().is_empty() {
+            println!("Taking over stale lock left by {}", stale.trim());
+        }
+        file.set_len(0)?;
+        write!(
+            file,
+            "pid {} since {}",
+            process::id(),
+            Local::now().to_rfc3339()
+        )?;
+        Ok(MirrorLock { file })
+    }
+}
+
+impl Drop for MirrorLock {
+    fn drop(&mut self) {
+        // Dropping the file releases the lock
+        let _ = self.file.set_len(0);
+    }
+}
@@ -1,18 +1,39 @@
 #![forbid(unsafe_code)]
 
-use anyhow::{anyhow, Error};
-use chrono::{Duration, Local, NaiveDate};
-use clap::Parser;
+use anyhow::{anyhow, Context, Error};
+use chrono::{Duration, Local};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use config::{Config, ConfigCommand};
 use filebuffer::FileBuffer;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use ring::digest;
+use serde::Deserialize;
 use std::collections::HashSet;
-use std::fs::{copy, create_dir_all, read_dir, remove_dir_all, remove_file, File};
+use std::fs::{copy, create_dir_all, hard_link, read, remove_dir_all, remove_file, rename, File};
 use std::io::{Read, Write};
-use std::path::{Component, Path, PathBuf};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::OnceLock;
+use storage::Storage;
 use toml::Value;
 use url::Url;
 
+mod bundle;
+mod cancel;
+mod cas;
+mod config;
+mod daemon;
+mod gc;
+mod gpg;
+mod history;
+mod lock;
+mod s3;
+mod storage;
+mod usage;
+mod verify;
+
 const RELEASE_CHANNELS: [&str; 3] = ["stable", "beta", "nightly"];
 
 // rustc --print target-list | awk '{print "    \"" $1 "\","}'
@@ -292,29 +313,84 @@ const TARGETS: [&str; 271] = [
 
 const DEFAULT_UPSTREAM_URL: &str = "https://static.rust-lang.org/";
 
+/// Directory of the orig directory that manifests are downloaded into before
+/// they are checked
+const INCOMING_DIR: &str = ".incoming";
+
+static HTTP_CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+
 fn file_sha256(file_path: &Path) -> Option<String> {
     let file = Path::new(file_path);
     if file.exists() {
-        let buffer = FileBuffer::open(&file).unwrap();
+        let buffer = FileBuffer::open(file).unwrap();
         Some(hex::encode(digest::digest(&digest::SHA256, &buffer)))
     } else {
         None
     }
 }
 
+/// Directory of an upstream given as a file:// url or a plain path
+fn local_upstream(upstream_url: &str) -> Option<PathBuf> {
+    match Url::parse(upstream_url) {
+        Ok(url) if url.scheme() == "file" => url.to_file_path().ok(),
+        Ok(_) => None,
+        Err(_) => Some(PathBuf::from(upstream_url)),
+    }
+}
+
+/// Link a file from a local upstream into place, or copy it across filesystems.
+/// On Linux, `copy` reflinks where the filesystem supports it.
+fn link_or_copy(from: &Path, to: &Path) -> Result<(), Error> {
+    create_dir_all(to.parent().unwrap())?;
+    if to.symlink_metadata().is_ok() {
+        // Never write through an existing link, it may be shared with upstream
+        remove_file(to)?;
+    }
+    if hard_link(from, to).is_err() {
+        copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Path of a file in a local upstream
+fn local_source(root: &Path, path: &str) -> Option<PathBuf> {
+    // An unpacked offline bundle keeps manifests and files apart
+    let candidates = if root.join(bundle::INDEX_NAME).exists() {
+        vec![root.join("orig").join(path), root.join("mirror").join(path)]
+    } else {
+        vec![root.join(path)]
+    };
+    candidates.into_iter().find(|source| source.is_file())
+}
+
 fn download(upstream_url: &str, dir: &str, path: &str) -> Result<PathBuf, Error> {
-    let manifest = format!("{}{}", upstream_url, path);
-    let mut response = reqwest::blocking::get(&manifest)?;
     let mirror = Path::new(dir);
-    let file_path = mirror.join(&path);
-    create_dir_all(file_path.parent().unwrap())?;
-    let mut dest = File::create(file_path)?;
+    let file_path = mirror.join(path);
 
-    println!("File /{} downloading", path);
+    if let Some(root) = local_upstream(upstream_url) {
+        let source = local_source(&root, path).ok_or_else(|| anyhow!("Not found"))?;
+        link_or_copy(&source, &file_path)?;
+        println!("File /{} linked from {}", path, source.display());
+        return Ok(file_path);
+    }
+
+    let manifest = format!("{}{}", upstream_url, path);
+    let mut response = HTTP_CLIENT
+        .get_or_init(reqwest::blocking::Client::new)
+        .get(&manifest)
+        .send()?
+        .error_for_status()?;
     let length = match response.content_length() {
         None => return Err(anyhow!("Not found")),
         Some(l) => l,
     };
+    create_dir_all(file_path.parent().unwrap())?;
+    // Download aside and rename, so an existing file is replaced rather than
+    // overwritten in place
+    let part_path = mirror.join(format!("{}.part", path));
+    let mut dest = File::create(&part_path)?;
+
+    println!("File /{} downloading", path);
     let pb = ProgressBar::new(length);
     pb.set_style(ProgressStyle::default_bar()
         .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} (ETA {eta_precise})")?
@@ -324,6 +400,13 @@ fn download(upstream_url: &str, dir: &str, path: &str) -> Result<PathBuf, Error>
     let mut read = 0;
 
     while read < length {
+        if cancel::is_cancelled() {
+            // Never leave a partial file behind
+            pb.finish_and_clear();
+            drop(dest);
+            remove_file(&part_path)?;
+            return Err(cancel::Cancelled.into());
+        }
         let len = response.read(&mut buffer)?;
         dest.write_all(&buffer[..len])?;
         read += len as u64;
@@ -331,8 +414,214 @@ fn download(upstream_url: &str, dir: &str, path: &str) -> Result<PathBuf, Error>
     }
 
     pb.finish_and_clear();
+    rename(part_path, &file_path)?;
     println!("File /{} downloaded", path);
-    Ok(mirror.join(path))
+    Ok(file_path)
+}
+
+/// Download a file from the first upstream that has it
+fn download_any(upstream_urls: &[String], dir: &str, path: &str) -> Result<PathBuf, Error> {
+    let mut last_error = anyhow!("No upstream given");
+    for upstream_url in upstream_urls {
+        match download(upstream_url, dir, path) {
+            Ok(file_path) => return Ok(file_path),
+            Err(err) if cancel::is_cancel_error(&err) => return Err(err),
+            Err(err) => {
+                println!("Failed to fetch /{} from {}: {}", path, upstream_url, err);
+                last_error = err;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Size of a file on the first upstream that has it, without downloading it
+fn remote_size(upstream_urls: &[String], path: &str) -> Option<u64> {
+    upstream_urls
+        .iter()
+        .find_map(|upstream_url| match local_upstream(upstream_url) {
+            Some(root) => Some(local_source(&root, path)?.metadata().ok()?.len()),
+            None => {
+                let response = HTTP_CLIENT
+                    .get_or_init(reqwest::blocking::Client::new)
+                    .head(format!("{}{}", upstream_url, path))
+                    .send()
+                    .ok()?;
+                if !response.status().is_success() {
+                    return None;
+                }
+                response
+                    .headers()
+                    .get(reqwest::header::CONTENT_LENGTH)?
+                    .to_str()
+                    .ok()?
+                    .parse()
+                    .ok()
+            }
+        })
+}
+
+/// Download a file from the first upstream that serves it with the expected checksum
+fn download_verified(
+    upstream_urls: &[String],
+    dir: &str,
+    path: &str,
+    sha256: &str,
+) -> Result<PathBuf, Error> {
+    for upstream_url in upstream_urls {
+        match download(upstream_url, dir, path) {
+            Ok(file_path) => {
+                if file_sha256(&file_path).as_deref() == Some(sha256) {
+                    return Ok(file_path);
+                }
+                println!(
+                    "File /{} from {} does not match its checksum",
+                    path, upstream_url
+                );
+                remove_file(file_path)?;
+            }
+            Err(err) if cancel::is_cancel_error(&err) => return Err(err),
+            Err(err) => println!("Failed to fetch /{} from {}: {}", path, upstream_url, err),
+        }
+    }
+    Err(anyhow!("No upstream serves a valid copy of /{}", path))
+}
+
+/// Relative path of an artifact from its url in a manifest.
+///
+/// Artifacts always live under `dist/`, so everything in front of it is
+/// dropped. This maps urls rewritten by another rustup-mirror served under
+/// a sub-path back to the same layout as static.rust-lang.org.
+fn url_to_path(url: &str) -> Result<String, Error> {
+    let url = Url::parse(url)?;
+    let path = url.path().replace("%20", " ");
+    match path.rfind("/dist/") {
+        Some(idx) => Ok(path[idx + 1..].to_string()),
+        None => Ok(path[1..].to_string()),
+    }
+}
+
+/// Parse a duration such as `6h`, `30m` or `1d`, plain numbers are seconds
+fn parse_duration(duration: &str) -> Result<std::time::Duration, Error> {
+    let duration = duration.trim();
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => duration.split_at(idx),
+        None => (duration, "s"),
+    };
+    let seconds = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow!("Unknown duration unit in {}", duration)),
+    };
+    let number: u64 = number.parse()?;
+    number
+        .checked_mul(seconds)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| anyhow!("Duration {} is too long", duration))
+}
+
+/// Parse a size such as `4G`, `512M` or `1048576` into bytes
+fn parse_size(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => size.split_at(idx),
+        None => (size, ""),
+    };
+    let shift = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches("IB")
+        .trim_end_matches('B')
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(anyhow!("Unknown size unit in {}", size)),
+    };
+    let number: u64 = number.parse()?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Size {} is too large", size))
+}
+
+/// Collect relative paths and sha256 checksums of artifacts referenced by a
+/// manifest, optionally restricted to some targets
+fn manifest_artifacts(
+    value: &Value,
+    filter_targets: Option<&HashSet<&String>>,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut artifacts = vec![];
+    let pkgs = value["pkg"]
+        .as_table()
+        .ok_or_else(|| anyhow!("Manifest has no pkg table"))?;
+    for pkg in pkgs.values() {
+        let pkg_targets = pkg["target"]
+            .as_table()
+            .ok_or_else(|| anyhow!("Package has no target table"))?;
+        for (target, pkg_target) in pkg_targets {
+            if let Some(filter_targets) = filter_targets
+                && !(filter_targets.contains(target) || *target == "*")
+            {
+                continue;
+            }
+            if pkg_target["available"].as_bool() != Some(true) {
+                continue;
+            }
+
+            for prefix in ["", "xz_"] {
+                let (Some(url), Some(hash)) = (
+                    pkg_target
+                        .get(format!("{}url", prefix))
+                        .and_then(Value::as_str),
+                    pkg_target
+                        .get(format!("{}hash", prefix))
+                        .and_then(Value::as_str),
+                ) else {
+                    continue;
+                };
+                artifacts.push((url_to_path(url)?, hash.to_string()));
+            }
+        }
+    }
+    Ok(artifacts)
+}
+
+/// Write a rewritten channel manifest and its checksum into the dated
+/// directory of the mirror, and also as the channel head if `update_head` is set
+fn publish_manifest(
+    storage: &dyn Storage,
+    channel: &str,
+    value: &Value,
+    update_head: bool,
+) -> Result<(), Error> {
+    let output = toml::to_string(value)?;
+    let sha256 = hex::encode(digest::digest(&digest::SHA256, output.as_bytes()));
+    let date = value["date"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Manifest of channel {} has no date", channel))?;
+
+    // The head is written last, so it never points to a missing dated manifest
+    let mut names = vec![format!("dist/{}/channel-rust-{}.toml", date, channel)];
+    if update_head {
+        names.push(format!("dist/channel-rust-{}.toml", channel));
+    }
+
+    for name in names {
+        println!("Producing /{}", name);
+        storage.put(&name, output.as_bytes())?;
+
+        let sha256_name = format!("{}.sha256", name);
+        println!("Producing /{}", sha256_name);
+        storage.put(
+            &sha256_name,
+            format!("{}  channel-rust-{}.toml", sha256, channel).as_bytes(),
+        )?;
+    }
+    Ok(())
 }
 
 #[derive(Parser)]
@@ -343,21 +632,190 @@ fn download(upstream_url: &str, dir: &str, path: &str) -> Result<PathBuf, Error>
 )]
 struct Cli {
     /// Where to store original manifest
-    #[arg(short, long, default_value = "./orig")]
+    #[arg(short, long, default_value = "./orig", global = true)]
     orig: String,
 
     /// Where to store mirror files
-    #[arg(short, long, default_value = "./mirror")]
+    #[arg(short, long, default_value = "./mirror", global = true)]
     mirror: String,
 
     /// Where mirror is served
-    #[arg(short, long, default_value = "http://127.0.0.1:8000")]
+    #[arg(short, long, default_value = "http://127.0.0.1:8000", global = true)]
     url: String,
 
+    /// Read settings from this TOML file, command line flags take precedence
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    sync: SyncArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sync the mirror from upstream (the default when no subcommand is given)
+    Sync(SyncArgs),
+
+    /// Export selected channels into an offline bundle
+    Export(bundle::ExportArgs),
+
+    /// Import an offline bundle into the mirror
+    Import(bundle::ImportArgs),
+
+    /// Sync periodically, optionally serving the mirror
+    Daemon(daemon::DaemonArgs),
+
+    /// Report disk usage of the mirror per channel
+    Usage,
+
+    /// Collect garbage without syncing
+    Gc(gc::GcArgs),
+
+    /// Rehash the files of all published channels and report problems
+    Verify(verify::VerifyArgs),
+
+    /// Work with the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+impl Cli {
+    /// Sync settings of the command, if it syncs at all
+    fn sync_args(&self) -> Option<&SyncArgs> {
+        match &self.command {
+            None => Some(&self.sync),
+            Some(Command::Sync(args)) => Some(args),
+            Some(Command::Daemon(args)) => Some(&args.sync),
+            _ => None,
+        }
+    }
+
+    /// Profile selection of the command, if it works on a mirror
+    fn profile_args(&self) -> Option<&ProfileArgs> {
+        match &self.command {
+            Some(Command::Gc(args)) => Some(&args.profiles),
+            Some(Command::Verify(args)) => Some(&args.profiles),
+            _ => self.sync_args().map(|args| &args.profiles),
+        }
+    }
+}
+
+/// Which profiles of the config file to run
+#[derive(Args)]
+struct ProfileArgs {
+    /// Run this profile of the config file
+    #[arg(long, conflicts_with = "all")]
+    profile: Option<String>,
+
+    /// Run all profiles of the config file
+    #[arg(long)]
+    all: bool,
+}
+
+/// Where the mirror is published
+#[derive(Args)]
+pub struct StorageArgs {
+    /// Wait for another run holding the mirror lock instead of failing
+    #[arg(long)]
+    wait: bool,
+
+    /// Publish into an S3-compatible bucket instead, e.g. s3://bucket/prefix.
+    /// Files then only stay in --mirror while they are uploaded
+    #[arg(long)]
+    storage: Option<String>,
+
+    /// Endpoint of the S3-compatible service, e.g. http://127.0.0.1:9000,
+    /// AWS_ENDPOINT_URL by default
+    #[arg(long)]
+    s3_endpoint: Option<String>,
+
+    /// Region of the bucket, AWS_REGION or us-east-1 by default
+    #[arg(long)]
+    s3_region: Option<String>,
+}
+
+/// What garbage collection keeps
+#[derive(Args)]
+pub struct RetentionArgs {
     /// Keep how many days of nightly toolchains, e.g. 365
     #[arg(short, long)]
     gc: Option<i64>,
 
+    /// Keep the last N nightly toolchains, in addition to those of --gc days
+    #[arg(long, value_name = "N")]
+    keep_nightlies: Option<usize>,
+
+    /// Keep the last N beta toolchains
+    #[arg(long, value_name = "N")]
+    keep_betas: Option<usize>,
+
+    /// Keep stable releases since this version, e.g. 1.70, which syncs also
+    /// publish by version, e.g. channel-rust-1.79.0.toml
+    #[arg(long, value_name = "VERSION", value_parser = gc::parse_version)]
+    keep_stable_since: Option<gc::Version>,
+
+    /// Keep the toolchains listed in this file, one per line, e.g. nightly-2024-01-04
+    #[arg(long)]
+    pin_file: Option<PathBuf>,
+
+    /// Keep the current and N previous versions of rustup-init in rustup/archive,
+    /// all of them by default
+    #[arg(long, value_name = "N")]
+    keep_rustup_versions: Option<usize>,
+
+    /// Keep the last N distinct upstream manifests of each channel, and their
+    /// signatures, below history/ in the orig directory
+    #[arg(long, value_name = "N")]
+    orig_history: Option<usize>,
+
+    /// Also delete files that no kept manifest or rustup release refers to,
+    /// such as orphan checksums and partial downloads
+    #[arg(long)]
+    sweep: bool,
+
+    /// Download files into this cache first and link them into the mirror,
+    /// so mirrors sharing it only fetch each file once. Garbage collection
+    /// prunes the files no mirror links to
+    #[arg(long)]
+    cache: Option<String>,
+}
+
+/// Where files are fetched from
+#[derive(Args)]
+pub struct UpstreamArgs {
+    /// Upstream url(s) to sync from, each can also be a file:// url or a local directory.
+    /// Manifests come from the first one, files from whichever serves them intact first
+    #[arg(short = 'U', long, value_delimiter = ',', default_values_t = [DEFAULT_UPSTREAM_URL.to_string()])]
+    upstream_url: Vec<String>,
+
+    /// Proxy for all requests, e.g. socks5://127.0.0.1:1080
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Timeout of each request in seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+}
+
+#[derive(Args)]
+struct SyncArgs {
+    #[command(flatten)]
+    profiles: ProfileArgs,
+
+    #[command(flatten)]
+    upstream: UpstreamArgs,
+
+    #[command(flatten)]
+    retention: RetentionArgs,
+
+    #[command(flatten)]
+    storage: StorageArgs,
+
     /// Which release channel(s) to mirror, e.g. stable,nightly
     #[arg(short, long, value_delimiter = ',', default_values_t = RELEASE_CHANNELS.map(String::from))]
     channels: Vec<String>,
@@ -366,128 +824,524 @@ struct Cli {
     #[arg(short, long, value_delimiter = ',', default_values_t = TARGETS.map(String::from))]
     targets: Vec<String>,
 
-    /// Upstream url to sync from
-    #[arg(short = 'U', long, default_value_t = DEFAULT_UPSTREAM_URL.to_string())]
-    upstream_url: String,
+    /// Which components to mirror, e.g. rustc,cargo,rust-std, all of them by default
+    #[arg(long, value_delimiter = ',')]
+    components: Option<Vec<String>>,
+
+    /// Refuse to grow the mirror beyond this size, e.g. 500G
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// What to do when the download does not fit into --max-size or the free space
+    #[arg(long, value_enum, default_value_t = SpacePolicy::Refuse)]
+    space_policy: SpacePolicy,
+
+    /// Store files once by checksum below .cas/sha256, and publish them as links
+    #[arg(long, value_enum)]
+    cas: Option<cas::CasMode>,
+
+    /// Only fetch manifests and report what would be downloaded and deleted,
+    /// without touching the mirror
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Hash the files already in the mirror instead of trusting their checksum
+    /// files, and download corrupt ones again
+    #[arg(long)]
+    rehash: bool,
+
+    /// Check the GPG signature of each channel manifest and refuse to mirror
+    /// it if it does not match
+    #[arg(long)]
+    verify_signatures: bool,
+
+    /// Armored public key the channel manifests are signed with, the Rust
+    /// release key by default
+    #[arg(long, value_name = "FILE")]
+    gpg_key: Option<PathBuf>,
 }
 
-fn main() {
-    let args = Cli::parse();
-
-    let orig_path = &args.orig;
-    let mirror_path = &args.mirror;
-    let mirror_url = &args.url;
-    let upstream_url = &args.upstream_url;
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SpacePolicy {
+    /// Fail before downloading anything
+    Refuse,
+    /// Collect garbage before downloading, and fail if it is still not enough
+    Gc,
+}
 
-    let parsed_gc_days = args.gc.map(|parsed_days| {
+/// What garbage collection keeps, besides the versioned heads of channels
+/// mirrored by name
+fn retention(cli: &Cli, args: &RetentionArgs, channels: &[String]) -> Result<gc::Retention, Error> {
+    let nightly_cutoff = args.gc.map(|parsed_days| {
         let mut day = Local::now().date_naive();
         day -= Duration::days(parsed_days);
-        println!("Nightly before {} will be deleted", day);
         day
     });
+    Ok(gc::Retention {
+        nightly_cutoff,
+        keep_nightlies: args.keep_nightlies,
+        keep_betas: args.keep_betas,
+        stable_since: args.keep_stable_since,
+        channels: channels.to_vec(),
+        pinned: match &args.pin_file {
+            Some(pin_file) => gc::read_pins(pin_file)?,
+            None => vec![],
+        },
+        keep_rustup_versions: args.keep_rustup_versions,
+        rustup_version: None,
+        orig_history: args
+            .orig_history
+            .map(|keep| (PathBuf::from(&cli.orig), keep)),
+        sweep: args.sweep,
+        cache: args.cache.as_ref().map(PathBuf::from),
+    })
+}
 
-    let channels = args.channels;
+/// Versioned channels a stable manifest is also published as, e.g. 1.79.0
+/// and 1.79, if the release is kept by `--keep-stable-since`
+fn version_channels(channel: &str, value: &Value, since: Option<gc::Version>) -> Vec<String> {
+    let version = value
+        .get("pkg")
+        .and_then(|pkg| pkg.get("rustc"))
+        .and_then(|rustc| rustc.get("version"))
+        .and_then(Value::as_str)
+        .and_then(|version| version.split_whitespace().next())
+        .and_then(|version| gc::parse_version(version).ok());
+    match (channel, since, version) {
+        ("stable", Some(since), Some((major, minor, patch))) if (major, minor, patch) >= since => {
+            vec![
+                format!("{}.{}.{}", major, minor, patch),
+                format!("{}.{}", major, minor),
+            ]
+        }
+        _ => vec![],
+    }
+}
+
+/// Set up the HTTP client shared by all downloads
+fn init_client(args: &UpstreamArgs) -> Result<(), Error> {
+    let mut builder = reqwest::blocking::Client::builder();
+    if let Some(proxy) = &args.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    if let Some(timeout) = args.timeout {
+        builder = builder.timeout(std::time::Duration::from_secs(timeout));
+    }
+    // Keep the client of an earlier sync in the same process
+    let _ = HTTP_CLIENT.set(builder.build()?);
+    Ok(())
+}
+
+fn main() {
+    let matches = Cli::command().get_matches();
+    // Sync flags in front of a subcommand would be silently ignored
+    if matches.subcommand().is_some() {
+        let sync_args = SyncArgs::augment_args(clap::Command::new("sync"));
+        if let Some(arg) = sync_args.get_arguments().find(|arg| {
+            matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+        }) {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "--{} must be given after the subcommand",
+                        arg.get_long().unwrap_or_default()
+                    ),
+                )
+                .exit();
+        }
+    }
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    let config = cli.config.as_ref().map(|path| {
+        Config::load(path).unwrap_or_else(|err| {
+            eprintln!("{:#}", err);
+            exit(1);
+        })
+    });
+
+    let profiles = match (cli.profile_args(), &config) {
+        (Some(args), Some(config)) if args.all => {
+            config.profile_names().into_iter().map(Some).collect()
+        }
+        (Some(args), Some(_)) if args.profile.is_some() => vec![args.profile.clone()],
+        (Some(args), None) if args.all || args.profile.is_some() => {
+            eprintln!("Profiles need a config file, use --config");
+            exit(1);
+        }
+        _ => vec![None],
+    };
+
+    if let Err(err) = cancel::install() {
+        eprintln!("Unable to handle signals: {:#}", err);
+    }
+
+    let mut clis = vec![];
+    for profile in profiles {
+        let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        if let Some(config) = &config {
+            let config = match &profile {
+                Some(profile) => config.profile(profile).unwrap_or_else(|err| {
+                    eprintln!("{:#}", err);
+                    exit(1);
+                }),
+                None => config.clone(),
+            };
+            config.apply(&mut cli, &matches);
+        }
+        clis.push((profile, cli));
+    }
+
+    if let Some(Command::Daemon(args)) = &cli.command {
+        if let Err(err) = daemon::daemon(&clis, args) {
+            fail(err);
+        }
+        return;
+    }
+
+    // A failing profile does not keep the others from running
+    let mut failed = vec![];
+    for (profile, cli) in &clis {
+        if let Some(profile) = profile {
+            println!("Running profile {}", profile);
+        }
+        match (run(cli), profile) {
+            (Ok(()), _) => {}
+            (Err(err), Some(profile)) if clis.len() > 1 && !cancel::is_cancel_error(&err) => {
+                eprintln!("Profile {} failed: {:#}", profile, err);
+                failed.push(profile.as_str());
+            }
+            (Err(err), _) => fail(err),
+        }
+    }
+    if !failed.is_empty() {
+        eprintln!(
+            "{} of {} profiles failed: {}",
+            failed.len(),
+            clis.len(),
+            failed.join(", ")
+        );
+        exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), Error> {
+    match &cli.command {
+        None => sync(cli, &cli.sync),
+        Some(Command::Sync(args)) => sync(cli, args),
+        Some(Command::Export(args)) => bundle::export(cli, args),
+        Some(Command::Import(args)) => bundle::import(cli, args),
+        Some(Command::Daemon(_)) => unreachable!("daemon is started by main"),
+        Some(Command::Usage) => usage::usage(cli),
+        Some(Command::Gc(args)) => gc::gc(cli, args),
+        Some(Command::Verify(args)) => verify::verify(cli, args),
+        Some(Command::Config {
+            command: ConfigCommand::Check,
+        }) => match &cli.config {
+            Some(path) => {
+                println!("Config file {} is valid", path.display());
+                Ok(())
+            }
+            None => Err(anyhow!("No config file given, use --config")),
+        },
+    }
+}
+
+/// Report an error and exit, with a distinct status if the run was cancelled
+fn fail(err: Error) -> ! {
+    if cancel::is_cancel_error(&err) {
+        eprintln!("Cancelled, incomplete channels were not published");
+        exit(cancel::EXIT_CANCELLED);
+    }
+    eprintln!("{:#}", err);
+    exit(1);
+}
+
+fn sync(cli: &Cli, args: &SyncArgs) -> Result<(), Error> {
+    if !args.dry_run {
+        return sync_with_orig(cli, args, &cli.orig);
+    }
+    // Keep the original manifests of the last real sync, they may be exported
+    let orig = std::env::temp_dir().join(format!("rustup-mirror-dry-run-{}", std::process::id()));
+    let result = sync_with_orig(cli, args, &orig.to_string_lossy());
+    let _ = remove_dir_all(orig);
+    result
+}
+
+/// An artifact of a channel manifest that is missing from the mirror
+struct Pending {
+    /// Path relative to the mirror
+    path: String,
+    sha256: String,
+    /// Whether the cache already has a verified copy
+    cached: bool,
+}
+
+/// Publish a file downloaded into the mirror directory, which only stages it
+/// when the mirror is stored elsewhere
+fn publish_staged(storage: &dyn Storage, path: &str, file: &Path) -> Result<(), Error> {
+    storage.put_file(path, file)?;
+    if storage.local_path(path).is_none() {
+        remove_file(file)?;
+    }
+    Ok(())
+}
+
+/// Why downloading `needed` more bytes does not fit, if it does not
+fn space_problem(
+    storage: &dyn Storage,
+    download_dir: &str,
+    needed: u64,
+    max_size: Option<u64>,
+) -> Result<Option<String>, Error> {
+    if let Some(max_size) = max_size {
+        let size = storage.size()? + needed;
+        if size > max_size {
+            return Ok(Some(format!(
+                "Mirror would grow to {}, over the quota of {}",
+                HumanBytes(size),
+                HumanBytes(max_size)
+            )));
+        }
+    }
+    // The directory may not exist before the first sync
+    let dir = Path::new(download_dir)
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    let available = fs4::available_space(dir)
+        .with_context(|| format!("Unable to get free space of {}", dir.display()))?;
+    if needed > available {
+        return Ok(Some(format!(
+            "Downloading {} needs more than the {} free in {}",
+            HumanBytes(needed),
+            HumanBytes(available),
+            download_dir
+        )));
+    }
+    Ok(None)
+}
+
+/// Report a file a dry run would download, with its size if upstream tells
+fn plan_download(upstream_urls: &[String], path: &str) -> Option<u64> {
+    let size = remote_size(upstream_urls, path);
+    match size {
+        Some(size) => println!("Would download /{} ({})", path, HumanBytes(size)),
+        None => println!("Would download /{} (unknown size)", path),
+    }
+    size
+}
+
+fn sync_with_orig(cli: &Cli, args: &SyncArgs, orig_path: &str) -> Result<(), Error> {
+    init_client(&args.upstream)?;
+    let _lock = if args.dry_run {
+        None
+    } else {
+        Some(lock::MirrorLock::acquire(&cli.mirror, args.storage.wait)?)
+    };
+
+    let mirror_path = &cli.mirror;
+    let mirror_url = &cli.url;
+    let storage = storage::open(mirror_path, &args.storage)?;
+    if args.cas.is_some() && storage.local_path("").is_none() {
+        return Err(anyhow!("--cas needs the mirror on the local filesystem"));
+    }
+    let upstream_urls = &args.upstream.upstream_url;
+    // Channel manifests always come from the authoritative upstream
+    let upstream_url = &upstream_urls[0];
+
+    let mut retention = retention(cli, &args.retention, &args.channels)?;
+    let signing_key = if args.verify_signatures {
+        Some(gpg::load_key(args.gpg_key.as_deref())?)
+    } else {
+        None
+    };
+    if let Some(day) = retention.nightly_cutoff {
+        println!("Nightly before {} will be deleted", day);
+    }
+
+    let channels = &args.channels;
     let filter_targets = args
         .targets
         .iter()
         .collect::<std::collections::HashSet<_>>();
+    let filter_components = args
+        .components
+        .as_ref()
+        .map(|components| components.iter().collect::<HashSet<_>>());
 
     let mut all_targets = HashSet::new();
 
     // All referenced files
     let mut referenced = HashSet::new();
 
-    // Fetch rust components
+    // Sizes of files to download, and how many are already there
+    let mut planned = vec![];
+    let mut existing = 0;
+
+    // Fetch and filter channel manifests, deciding what to download
+    let mut fetched = vec![];
+    let mut pending_paths = HashSet::new();
+    // Manifests are downloaded aside and only replace the last good ones in
+    // orig once their checksum and signature are checked
+    let incoming = Path::new(orig_path)
+        .join(INCOMING_DIR)
+        .to_string_lossy()
+        .into_owned();
     for channel in channels.iter() {
+        cancel::check()?;
         let name = format!("dist/channel-rust-{}.toml", channel);
-        let file_path = download(upstream_url, orig_path, &name).unwrap();
+        let file_path = download(upstream_url, &incoming, &name)?;
         let sha256_name = format!("dist/channel-rust-{}.toml.sha256", channel);
-        let sha256_file_path = download(upstream_url, orig_path, &sha256_name).unwrap();
+        let sha256_file_path = download(upstream_url, &incoming, &sha256_name)?;
 
-        let mut file = File::open(file_path.clone()).unwrap();
+        let mut file = File::open(file_path.clone())?;
         let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
+        file.read_to_string(&mut data)?;
 
-        let mut sha256_file = File::open(sha256_file_path.clone()).unwrap();
+        let mut sha256_file = File::open(sha256_file_path.clone())?;
         let mut sha256_data = String::new();
-        sha256_file.read_to_string(&mut sha256_data).unwrap();
-        assert_eq!(
-            file_sha256(file_path.as_path()).unwrap(),
-            &sha256_data[..64]
-        );
-
-        let mut value = data.parse::<Value>().unwrap();
-        assert_eq!(value["manifest-version"].as_str(), Some("2"));
-        println!(
-            "Channel {} date {}",
-            channel,
-            value["date"].as_str().unwrap()
-        );
+        sha256_file.read_to_string(&mut sha256_data)?;
+        if file_sha256(file_path.as_path()).as_deref() != sha256_data.get(..64) {
+            return Err(anyhow!("Checksum mismatch for /{}", name));
+        }
+        let asc_name = format!("dist/channel-rust-{}.toml.asc", channel);
+        let mut signature = None;
+        if let Some(key) = &signing_key {
+            let asc_file_path = download(upstream_url, &incoming, &asc_name)?;
+            let asc = read(asc_file_path)?;
+            gpg::verify(key, data.as_bytes(), &asc)
+                .with_context(|| format!("Refusing to mirror /{}", name))?;
+            println!("Signature of /{} is valid", name);
+            signature = Some(asc);
+        }
+        for checked in [&name, &sha256_name]
+            .into_iter()
+            .chain(signature.as_ref().map(|_| &asc_name))
+        {
+            let dest = Path::new(orig_path).join(checked);
+            create_dir_all(dest.parent().unwrap())?;
+            rename(Path::new(&incoming).join(checked), dest)?;
+        }
 
-        let pkgs = value["pkg"].as_table_mut().unwrap();
+        let mut value: Value = toml::from_str(&data)?;
+        if value["manifest-version"].as_str() != Some("2") {
+            return Err(anyhow!("Unsupported manifest version of /{}", name));
+        }
+        if args.retention.orig_history.is_some() && !args.dry_run {
+            history::record(
+                Path::new(orig_path),
+                channel,
+                data.as_bytes(),
+                signature.as_deref(),
+            )?;
+        }
+        let date = value["date"]
+            .as_str()
+            .with_context(|| format!("Manifest /{} has no date", name))?
+            .to_string();
+        println!("Channel {} date {}", channel, date);
+        let mut pending = vec![];
+
+        let pkgs = value["pkg"]
+            .as_table_mut()
+            .context("Manifest has no pkg table")?;
         let keys: Vec<String> = pkgs.keys().cloned().collect();
         for pkg_name in keys {
-            let pkg = pkgs.get_mut(&pkg_name).unwrap().as_table_mut().unwrap();
-            let pkg_targets = pkg.get_mut("target").unwrap().as_table_mut().unwrap();
+            let wanted_pkg = filter_components
+                .as_ref()
+                .is_none_or(|components| components.contains(&pkg_name));
+            let pkg_targets = pkgs
+                .get_mut(&pkg_name)
+                .and_then(|pkg| pkg.get_mut("target"))
+                .and_then(Value::as_table_mut)
+                .with_context(|| format!("Package {} has no target table", pkg_name))?;
             for (target, pkg_target) in pkg_targets {
-                let pkg_target = pkg_target.as_table_mut().unwrap();
+                let pkg_target = pkg_target
+                    .as_table_mut()
+                    .context("Malformed target table")?;
 
-                // if we don't want to download this target
+                // if we don't want to download this target or component
                 // set available to false and do not download
                 // but we will keep this table in the toml, which is required for newer version of
                 // rustup
-                if !(filter_targets.contains(target) || *target == "*") {
-                    *pkg_target.get_mut("available").unwrap() = toml::Value::Boolean(false);
+                if !wanted_pkg || !(filter_targets.contains(target) || *target == "*") {
+                    pkg_target.insert("available".to_string(), Value::Boolean(false));
                     continue;
                 }
 
-                if pkg_target["available"].as_bool().unwrap() {
+                if pkg_target["available"].as_bool() == Some(true) {
                     all_targets.insert(target.clone());
 
                     let prefixes = ["", "xz_"];
                     for prefix in prefixes.iter() {
-                        let url =
-                            Url::parse(pkg_target[&format!("{}url", prefix)].as_str().unwrap())
-                                .unwrap();
-                        let mirror = Path::new(mirror_path);
-                        let file_name = url.path().replace("%20", " ");
-                        let file = mirror.join(&file_name[1..]);
-
-                        referenced.insert(normalize_path(&file));
-
-                        let hash_file = mirror.join(format!("{}.sha256", &file_name[1..]));
-                        let hash_file_cont =
-                            File::open(hash_file.clone()).ok().and_then(|mut f| {
-                                let mut cont = String::new();
-                                f.read_to_string(&mut cont).ok().map(|_| cont)
-                            });
-
-                        let hash_file_missing = hash_file_cont.is_none();
-                        let mut hash_file_cont =
-                            hash_file_cont.or_else(|| file_sha256(file.as_path()));
-
-                        let chksum_upstream =
-                            pkg_target[&format!("{}hash", prefix)].as_str().unwrap();
-
-                        let need_download = match hash_file_cont {
-                            Some(ref chksum) => chksum_upstream != chksum,
-                            None => true,
+                        let url = pkg_target[&format!("{}url", prefix)]
+                            .as_str()
+                            .context("Missing url in manifest")?;
+                        let file_name = format!("/{}", url_to_path(url)?);
+
+                        referenced.insert(file_name[1..].to_string());
+
+                        let hash_file = format!("{}.sha256", &file_name[1..]);
+                        let hash_file_cont = storage
+                            .get(&hash_file)?
+                            .map(|cont| String::from_utf8_lossy(&cont).into_owned());
+
+                        let chksum_upstream = pkg_target[&format!("{}hash", prefix)]
+                            .as_str()
+                            .context("Missing hash in manifest")?;
+
+                        let (hash_file_stale, hash_file_cont) = if args.rehash {
+                            let actual = verify::sha256_of(&*storage, &file_name[1..])?;
+                            if actual
+                                .as_deref()
+                                .is_some_and(|actual| actual != chksum_upstream)
+                            {
+                                println!("File {} is corrupt, downloading it again", file_name);
+                                if !args.dry_run && storage.local_path("").is_some() {
+                                    cas::drop_corrupt(Path::new(mirror_path), chksum_upstream)?;
+                                }
+                            }
+                            (hash_file_cont.as_deref() != Some(chksum_upstream), actual)
+                        } else {
+                            let hash_file_missing = hash_file_cont.is_none();
+                            let hash_file_cont = hash_file_cont
+                                .or_else(|| file_sha256(&storage.local_path(&file_name[1..])?));
+                            (hash_file_missing, hash_file_cont)
                         };
 
-                        if need_download {
-                            download(upstream_url, mirror_path, &file_name[1..]).unwrap();
-                            hash_file_cont = file_sha256(file.as_path());
-                            assert_eq!(Some(chksum_upstream), hash_file_cont.as_deref());
-                        } else {
+                        // A checksum left behind by a file that is gone does
+                        // not count, the file is linked from the store or
+                        // downloaded again
+                        let file_missing = storage
+                            .local_path(&file_name[1..])
+                            .is_some_and(|file| !file.is_file());
+                        let need_download = file_missing
+                            || match hash_file_cont {
+                                Some(ref chksum) => chksum_upstream != chksum,
+                                None => true,
+                            };
+
+                        if !need_download {
                             println!("File {} already downloaded, skipping", file_name);
-                        }
-
-                        if need_download || hash_file_missing {
-                            File::create(hash_file)
-                                .unwrap()
-                                .write_all(hash_file_cont.unwrap().as_bytes())
-                                .unwrap();
-                            println!("Writing checksum for file {}", file_name);
+                            existing += 1;
+                            if hash_file_stale && !args.dry_run {
+                                storage.put(&hash_file, chksum_upstream.as_bytes())?;
+                                println!("Writing checksum for file {}", file_name);
+                            }
+                            if let Some(mode) = args.cas.filter(|_| !args.dry_run) {
+                                // Move files of an existing mirror into the store
+                                let mirror = Path::new(mirror_path);
+                                cas::store(mirror, &file_name[1..], chksum_upstream, mode)?;
+                            }
+                        } else if pending_paths.insert(file_name.clone()) {
+                            // Versioned channels share files with stable
+                            pending.push(Pending {
+                                path: file_name[1..].to_string(),
+                                sha256: chksum_upstream.to_string(),
+                                cached: false,
+                            });
                         }
 
                         pkg_target.insert(
@@ -499,33 +1353,117 @@ fn main() {
             }
         }
 
-        let output = toml::to_string(&value).unwrap();
-        let path = Path::new(mirror_path).join(&name);
-        create_dir_all(path.parent().unwrap()).unwrap();
-        let mut file = File::create(path.clone()).unwrap();
-        println!("Producing /{}", name);
-        file.write_all(output.as_bytes()).unwrap();
+        // Keep the dated manifest, so that toolchains of this date stay
+        // installable and downstream mirrors can fetch it
+        let alt_name = format!("dist/{}/channel-rust-{}.toml", date, channel);
+        referenced.insert(alt_name);
+        for version in version_channels(channel, &value, args.retention.keep_stable_since) {
+            referenced.insert(format!("dist/{}/channel-rust-{}.toml", date, version));
+        }
+        fetched.push((channel, value, pending));
+    }
 
-        let sha256_new_file = file_sha256(&path).unwrap();
-        let sha256_new_file_path = Path::new(mirror_path).join(&sha256_name);
-        let mut file = File::create(sha256_new_file_path.clone()).unwrap();
-        println!("Producing /{}", sha256_name);
-        file.write_all(format!("{}  channel-rust-{}.toml", sha256_new_file, channel).as_bytes())
-            .unwrap();
+    // Estimate the download before starting it
+    let pending_count: usize = fetched.iter().map(|(_, _, pending)| pending.len()).sum();
+    if !args.dry_run && pending_count > 0 {
+        println!("Estimating size of {} files to download...", pending_count);
+    }
+    let mut stored = HashSet::new();
+    for (_, _, pending) in &mut fetched {
+        for item in pending.iter_mut() {
+            cancel::check()?;
+            if args.cas.is_some()
+                && (cas::object_path(Path::new(mirror_path), &item.sha256).is_file()
+                    || !stored.insert(item.sha256.clone()))
+            {
+                if args.dry_run {
+                    println!("Would link file /{} from store", item.path);
+                }
+                continue;
+            }
+            item.cached = args.retention.cache.as_ref().is_some_and(|cache| {
+                file_sha256(&Path::new(cache).join(&item.path)).as_deref()
+                    == Some(item.sha256.as_str())
+            });
+            if item.cached {
+                if args.dry_run {
+                    println!("Would link file /{} from cache", item.path);
+                }
+            } else if args.dry_run {
+                planned.push(plan_download(upstream_urls, &item.path));
+            } else {
+                planned.push(remote_size(upstream_urls, &item.path));
+            }
+        }
+    }
+    let needed: u64 = planned.iter().flatten().sum();
+    let unknown = planned.iter().filter(|size| size.is_none()).count();
+    println!(
+        "Estimated download: {} in {} files ({} of unknown size)",
+        HumanBytes(needed),
+        planned.len(),
+        unknown
+    );
 
-        let date = value["date"].as_str().unwrap();
+    let download_dir = args.retention.cache.as_deref().unwrap_or(mirror_path);
+    if let Some(problem) = space_problem(&*storage, download_dir, needed, args.max_size)? {
+        match args.space_policy {
+            _ if args.dry_run => println!("Sync would not fit: {}", problem),
+            SpacePolicy::Refuse => return Err(anyhow!("{}, refusing to sync", problem)),
+            SpacePolicy::Gc => {
+                println!("{}, collecting garbage before downloading", problem);
+                gc::plan(&*storage, &referenced, &[], &retention)?.apply(&*storage)?;
+                if let Some(problem) =
+                    space_problem(&*storage, download_dir, needed, args.max_size)?
+                {
+                    return Err(anyhow!("{}, refusing to sync", problem));
+                }
+            }
+        }
+    }
 
-        let alt_name = format!("dist/{}/channel-rust-{}.toml", date, channel);
-        let alt_path = Path::new(mirror_path).join(&alt_name);
-        create_dir_all(alt_path.parent().unwrap()).unwrap();
-        copy(path, alt_path).unwrap();
-        println!("Producing /{}", alt_name);
+    // Fetch rust components
+    for (channel, value, pending) in fetched.iter().filter(|_| !args.dry_run) {
+        let mirror = Path::new(mirror_path);
+        for item in pending {
+            // A channel cancelled halfway is never published
+            cancel::check()?;
+            let sha256_path = format!("{}.sha256", item.path);
+            if let Some(mode) = args.cas
+                && cas::link_stored(mirror, &item.path, &item.sha256, mode)?
+            {
+                println!("File /{} linked from store", item.path);
+                storage.put(&sha256_path, item.sha256.as_bytes())?;
+                continue;
+            }
+            match &args.retention.cache {
+                Some(cache) => {
+                    let cached = Path::new(cache).join(&item.path);
+                    if item.cached {
+                        println!("File /{} found in cache", item.path);
+                    } else {
+                        download_verified(upstream_urls, cache, &item.path, &item.sha256)?;
+                    }
+                    storage.put_file(&item.path, &cached)?;
+                }
+                None => {
+                    let file =
+                        download_verified(upstream_urls, mirror_path, &item.path, &item.sha256)?;
+                    publish_staged(&*storage, &item.path, &file)?;
+                }
+            }
+            if let Some(mode) = args.cas {
+                cas::store(mirror, &item.path, &item.sha256, mode)?;
+            }
+            storage.put(&sha256_path, item.sha256.as_bytes())?;
+            println!("Writing checksum for file /{}", item.path);
+        }
 
-        let alt_sha256_new_file_name =
-            format!("dist/{}/channel-rust-{}.toml.sha256", date, channel);
-        let alt_sha256_new_file_path = Path::new(mirror_path).join(&alt_sha256_new_file_name);
-        copy(sha256_new_file_path, alt_sha256_new_file_path).unwrap();
-        println!("Producing /{}", alt_sha256_new_file_name);
+        publish_manifest(&*storage, channel, value, true)?;
+        // Stays installable by version after the next release ships
+        for version in version_channels(channel, value, args.retention.keep_stable_since) {
+            publish_manifest(&*storage, &version, value, true)?;
+        }
     }
 
     // Fetch latest binary of rustup
@@ -539,35 +1477,38 @@ fn main() {
 
         let ext = if is_windows { ".exe" } else { "" };
 
-        if download(
-            upstream_url,
-            mirror_path,
-            &format!("rustup/dist/{}/rustup-init{}", target, ext),
-        )
-        .is_err()
-        {
-            println!("Failed to fetch rustup-init for target {}, ignored", target);
+        let path = format!("rustup/dist/{}/rustup-init{}", target, ext);
+        if args.dry_run {
+            planned.push(plan_download(upstream_urls, &path));
+            continue;
+        }
+        cancel::check()?;
+        match download_any(upstream_urls, mirror_path, &path) {
+            Ok(file) => publish_staged(&*storage, &path, &file)?,
+            Err(_) => println!("Failed to fetch rustup-init for target {}, ignored", target),
         }
     }
 
     // Fetch rustup self update
+    cancel::check()?;
     println!("Downloading rustup self update manifest...");
     let self_update_manifest_path =
-        download(upstream_url, orig_path, "rustup/release-stable.toml").unwrap();
+        download(upstream_url, orig_path, "rustup/release-stable.toml")?;
 
-    let mut self_update_manifest = File::open(self_update_manifest_path.clone()).unwrap();
+    let mut self_update_manifest = File::open(self_update_manifest_path.clone())?;
     let mut self_update_manifest_data = String::new();
-    self_update_manifest
-        .read_to_string(&mut self_update_manifest_data)
-        .unwrap();
-
-    let self_update_manifest_val = self_update_manifest_data.parse::<Value>().unwrap();
-    assert_eq!(
-        self_update_manifest_val["schema-version"].as_str(),
-        Some("1")
-    );
+    self_update_manifest.read_to_string(&mut self_update_manifest_data)?;
+
+    let self_update_manifest_val: Value = toml::from_str(&self_update_manifest_data)?;
+    if self_update_manifest_val["schema-version"].as_str() != Some("1") {
+        return Err(anyhow!(
+            "Unsupported schema version of rustup release manifest"
+        ));
+    }
 
-    let self_version = self_update_manifest_val["version"].as_str().unwrap();
+    let self_version = self_update_manifest_val["version"]
+        .as_str()
+        .context("Rustup release manifest has no version")?;
 
     for target in all_targets {
         if target == "*" {
@@ -578,115 +1519,51 @@ fn main() {
 
         let ext = if is_windows { ".exe" } else { "" };
 
-        if download(
-            upstream_url,
-            mirror_path,
-            &format!(
-                "rustup/archive/{}/{}/rustup-init{}",
-                self_version, target, ext
-            ),
-        )
-        .is_err()
-        {
-            println!("Failed to fetch rustup-init for target {}, ignored", target);
-        }
-    }
-
-    copy(
-        self_update_manifest_path,
-        Path::new(mirror_path).join("rustup/release-stable.toml"),
-    )
-    .unwrap();
-
-    // Garbage collect old nightly builds, and unreferenced stable/beta builds
-    for date_dir in read_dir(Path::new(mirror_path).join("dist")).expect("Unable to read dist dir")
-    {
-        let date_dir = date_dir.unwrap();
-        if !date_dir.file_type().unwrap().is_dir() {
-            // Is metadata
+        let path = format!(
+            "rustup/archive/{}/{}/rustup-init{}",
+            self_version, target, ext
+        );
+        if args.dry_run {
+            planned.push(plan_download(upstream_urls, &path));
             continue;
         }
-
-        let clear_nightly = if let Some(parsed_gc_days) = parsed_gc_days {
-            let dir_name = date_dir.file_name().into_string().unwrap();
-            let parsed_dir_name = NaiveDate::parse_from_str(&dir_name, "%Y-%m-%d").unwrap();
-            parsed_dir_name < parsed_gc_days
-        } else {
-            false
-        };
-
-        // Is there anyone left?
-        let mut perserve_dir = false;
-
-        for file in read_dir(date_dir.path()).expect("inner dir") {
-            let file = file.unwrap();
-            let fname = file.file_name();
-            let fname = fname.to_string_lossy();
-            if fname.ends_with(".sha256") {
-                // Is an hash, will be deleted alongside the hashed file
-                continue;
-            }
-
-            let canonicalized = file.path().canonicalize().unwrap();
-            let normalized = normalize_path(&file.path());
-
-            // Filter referenced artifacts. Manifests will never be referenced
-            let to_be_deleted = if referenced.contains(&normalized) {
-                false
-            } else if fname.find("nightly").is_some() {
-                // Is nightly artifact or manifest
-                clear_nightly
-            } else {
-                // Is stable/beta artifact or manifest, delete by default
-                true
-            };
-
-            if to_be_deleted {
-                // Delete artifact / manifest and its corresponding hash
-                println!("Deleting file {}[.sha256]", canonicalized.display());
-                remove_file(&canonicalized).unwrap();
-                // Ignore error if the hash is not deleted (e.g. there is no hash present)
-                let mut canonicalized = canonicalized;
-                canonicalized.set_file_name((fname + ".sha256").as_ref());
-                let _ = remove_file(canonicalized);
-            } else {
-                perserve_dir = true;
-            }
+        cancel::check()?;
+        match download_any(upstream_urls, mirror_path, &path) {
+            Ok(file) => publish_staged(&*storage, &path, &file)?,
+            Err(_) => println!("Failed to fetch rustup-init for target {}, ignored", target),
         }
+    }
 
-        if !perserve_dir {
-            println!(
-                "No useful file left in dir {}, removing the entire directory.",
-                date_dir.path().display()
-            );
-            remove_dir_all(date_dir.path()).unwrap();
+    // Garbage collect old nightly builds, and unreferenced stable/beta builds
+    // The new rustup release is only published afterwards
+    retention.rustup_version = Some(self_version.to_string());
+    // A dry run did not publish the new channel manifests
+    let replaced_channels: &[String] = if args.dry_run { channels } else { &[] };
+    let gc_plan = gc::plan(&*storage, &referenced, replaced_channels, &retention)?;
+
+    if args.dry_run {
+        gc_plan.report();
+        let known: u64 = planned.iter().flatten().sum();
+        let unknown = planned.iter().filter(|size| size.is_none()).count();
+        println!(
+            "Dry run: {} files to download ({}), {} already present, {} files, {} directories, {} store objects and {} orphan files to delete, {} unknown entries left alone",
+            planned.len(),
+            HumanBytes(known),
+            existing,
+            gc_plan.files.len(),
+            gc_plan.dirs.len(),
+            gc_plan.objects.len(),
+            gc_plan.orphans.len(),
+            gc_plan.unknown.len()
+        );
+        if unknown > 0 {
+            println!("Size of {} files is unknown", unknown);
         }
+        return Ok(());
     }
-}
 
-pub fn normalize_path(path: &Path) -> PathBuf {
-    let mut components = path.components().peekable();
-    let mut ret = if let Some(c @ Component::Prefix(..)) = components.peek().cloned() {
-        components.next();
-        PathBuf::from(c.as_os_str())
-    } else {
-        PathBuf::new()
-    };
+    storage.put_file("rustup/release-stable.toml", &self_update_manifest_path)?;
 
-    for component in components {
-        match component {
-            Component::Prefix(..) => unreachable!(),
-            Component::RootDir => {
-                ret.push(component.as_os_str());
-            }
-            Component::CurDir => {}
-            Component::ParentDir => {
-                ret.pop();
-            }
-            Component::Normal(c) => {
-                ret.push(c);
-            }
-        }
-    }
-    ret
+    cancel::check()?;
+    gc_plan.apply(&*storage)
 }
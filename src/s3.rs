@@ -0,0 +1,451 @@
+use crate::storage::Storage;
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, Utc};
+use reqwest::blocking::{Body, Client, Response};
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{Method, StatusCode};
+use ring::{digest, hmac};
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Paths and sizes of objects, relative to the mirror root
+type Objects = Vec<(String, u64)>;
+
+/// Payload hash of requests whose body is not signed
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// A mirror in a bucket of an S3-compatible object store, addressed path-style
+/// so that it works with any endpoint
+pub struct S3Storage {
+    endpoint: Url,
+    region: String,
+    bucket: String,
+    /// Key prefix, empty or ending with `/`
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    client: Client,
+}
+
+impl S3Storage {
+    /// Open a bucket given as `s3://bucket/prefix`. Credentials are taken from
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`.
+    pub fn new(url: &str, endpoint: Option<&str>, region: Option<&str>) -> Result<Self, Error> {
+        let url = Url::parse(url).with_context(|| format!("Invalid storage url {}", url))?;
+        if url.scheme() != "s3" {
+            return Err(anyhow!("Storage url {} is not an s3:// url", url));
+        }
+        let bucket = url
+            .host_str()
+            .ok_or_else(|| anyhow!("Storage url {} has no bucket", url))?
+            .to_string();
+        let prefix = match url.path().trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+
+        let region = region
+            .map(String::from)
+            .or_else(|| env::var("AWS_REGION").ok())
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = endpoint
+            .map(String::from)
+            .or_else(|| env::var("AWS_ENDPOINT_URL").ok())
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint =
+            Url::parse(&endpoint).with_context(|| format!("Invalid S3 endpoint {}", endpoint))?;
+
+        Ok(S3Storage {
+            endpoint,
+            region,
+            bucket,
+            prefix,
+            access_key: env::var("AWS_ACCESS_KEY_ID").context("AWS_ACCESS_KEY_ID is not set")?,
+            secret_key: env::var("AWS_SECRET_ACCESS_KEY")
+                .context("AWS_SECRET_ACCESS_KEY is not set")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            // Uploads of large files take longer than the default timeout
+            client: Client::builder().timeout(None).build()?,
+        })
+    }
+
+    /// Send a request signed with AWS Signature Version 4
+    fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Option<Body>,
+    ) -> Result<Response, Error> {
+        let uri = match key {
+            "" => format!("/{}", uri_encode(&self.bucket, true)),
+            key => format!(
+                "/{}/{}",
+                uri_encode(&self.bucket, true),
+                uri_encode(key, false)
+            ),
+        };
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or(""), port),
+            None => self.endpoint.host_str().unwrap_or("").to_string(),
+        };
+        let now = Utc::now();
+
+        // Sorted by name, as the canonical request needs them
+        let mut headers = vec![
+            ("host", host.clone()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_string()),
+            ("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let (canonical_request, signed_headers) =
+            canonical_request(method.as_str(), &uri, &query, &headers, UNSIGNED_PAYLOAD);
+        let (scope, signature) = signature(&self.secret_key, &self.region, now, &canonical_request);
+
+        let mut url = format!("{}://{}{}", self.endpoint.scheme(), host, uri);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        let mut request = self.client.request(method.clone(), &url).header(
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed_headers, signature
+            ),
+        );
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        let response = request.send()?;
+        if response.status().is_success() || response.status() == StatusCode::NOT_FOUND {
+            return Ok(response);
+        }
+        let status = response.status();
+        Err(anyhow!(
+            "S3 {} /{} failed with {}: {}",
+            method,
+            key,
+            status,
+            response.text().unwrap_or_default()
+        ))
+    }
+
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+
+    /// Keys and sizes of all objects below a prefix, relative to the mirror root
+    fn list_objects(&self, dir: &str) -> Result<Objects, Error> {
+        let prefix = self.key(dir);
+        let mut objects = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let response = self.request(Method::GET, "", &query, None)?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Err(anyhow!("Bucket {} does not exist", self.bucket));
+            }
+            let (page, next) = parse_listing(&response.text()?, &self.prefix)?;
+            objects.extend(page);
+            token = next;
+            if token.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+}
+
+impl Storage for S3Storage {
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+
+    fn get(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let response = self.request(Method::GET, &self.key(path), &[], None)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.bytes()?.to_vec()))
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let response = self.request(
+            Method::PUT,
+            &self.key(path),
+            &[],
+            Some(Body::from(data.to_vec())),
+        )?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(anyhow!("Bucket {} does not exist", self.bucket));
+        }
+        Ok(())
+    }
+
+    fn put_file(&self, path: &str, file: &Path) -> Result<(), Error> {
+        println!("Uploading /{}", path);
+        let body = Body::from(File::open(file)?);
+        let response = self.request(Method::PUT, &self.key(path), &[], Some(body))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(anyhow!("Bucket {} does not exist", self.bucket));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), Error> {
+        self.request(Method::DELETE, &self.key(path), &[], None)?;
+        Ok(())
+    }
+
+    fn delete_dir(&self, path: &str) -> Result<(), Error> {
+        for (path, _) in self.list_objects(&format!("{}/", path.trim_end_matches('/')))? {
+            self.delete(&path)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let dir = match dir.trim_end_matches('/') {
+            "" => String::new(),
+            dir => format!("{}/", dir),
+        };
+        Ok(self
+            .list_objects(&dir)?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect())
+    }
+
+    fn file_size(&self, path: &str) -> Result<Option<u64>, Error> {
+        let response = self.request(Method::HEAD, &self.key(path), &[], None)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok()))
+    }
+
+    fn size(&self) -> Result<u64, Error> {
+        Ok(self.list_objects("")?.iter().map(|(_, size)| size).sum())
+    }
+}
+
+/// Canonical request of AWS Signature Version 4 and the names of the signed
+/// headers, which must be given lowercase and sorted by name
+fn canonical_request(
+    method: &str,
+    uri: &str,
+    query: &str,
+    headers: &[(&str, String)],
+    payload_hash: &str,
+) -> (String, String) {
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, uri, query, canonical_headers, signed_headers, payload_hash
+    );
+    (canonical_request, signed_headers)
+}
+
+/// Credential scope and signature of a canonical request
+fn signature(
+    secret_key: &str,
+    region: &str,
+    now: DateTime<Utc>,
+    canonical_request: &str,
+) -> (String, String) {
+    let date = now.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        now.format("%Y%m%dT%H%M%SZ"),
+        scope,
+        hex::encode(digest::digest(
+            &digest::SHA256,
+            canonical_request.as_bytes()
+        ))
+    );
+    let mut signing_key = format!("AWS4{}", secret_key).into_bytes();
+    for part in [date.as_str(), region, "s3", "aws4_request"] {
+        signing_key = hmac_sha256(&signing_key, part.as_bytes());
+    }
+    let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+    (scope, signature)
+}
+
+/// Objects of one page of a ListObjectsV2 response, relative to the mirror
+/// root, and the continuation token of the next page if it is truncated
+fn parse_listing(xml: &str, prefix: &str) -> Result<(Objects, Option<String>), Error> {
+    let mut objects = vec![];
+    for contents in xml.split("<Contents>").skip(1) {
+        let key = xml_value(contents, "Key").ok_or_else(|| anyhow!("Malformed object listing"))?;
+        let size = xml_value(contents, "Size")
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(0);
+        if let Some(path) = key.strip_prefix(prefix) {
+            objects.push((path.to_string(), size));
+        }
+    }
+    let next = match xml_value(xml, "IsTruncated").as_deref() {
+        Some("true") => xml_value(xml, "NextContinuationToken"),
+        _ => None,
+    };
+    Ok((objects, next))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data).as_ref().to_vec()
+}
+
+/// Percent-encode everything but unreserved characters, and `/` unless it
+/// is part of a query
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Unescaped text of the first element with some tag
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(
+        xml[start..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// Hash of an empty payload
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn signs_the_published_get_object_example() {
+        // GET Object example of the AWS Signature Version 4 documentation
+        let headers = [
+            ("host", "examplebucket.s3.amazonaws.com".to_string()),
+            ("range", "bytes=0-9".to_string()),
+            ("x-amz-content-sha256", EMPTY_SHA256.to_string()),
+            ("x-amz-date", "20130524T000000Z".to_string()),
+        ];
+        let (canonical, signed_headers) =
+            canonical_request("GET", "/test.txt", "", &headers, EMPTY_SHA256);
+        assert_eq!(
+            canonical,
+            "GET\n/test.txt\n\n\
+             host:examplebucket.s3.amazonaws.com\n\
+             range:bytes=0-9\n\
+             x-amz-content-sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n\
+             x-amz-date:20130524T000000Z\n\n\
+             host;range;x-amz-content-sha256;x-amz-date\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(signed_headers, "host;range;x-amz-content-sha256;x-amz-date");
+        assert_eq!(
+            hex::encode(digest::digest(&digest::SHA256, canonical.as_bytes())),
+            "7344ae5b7ee6c3e7e6b0fe0640412a37625d1fbfff95c48bbb2dc43964946972"
+        );
+
+        let now = Utc.with_ymd_and_hms(2013, 5, 24, 0, 0, 0).unwrap();
+        let (scope, signature) = signature(
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            now,
+            &canonical,
+        );
+        assert_eq!(scope, "20130524/us-east-1/s3/aws4_request");
+        assert_eq!(
+            signature,
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn encodes_keys_and_queries() {
+        assert_eq!(
+            uri_encode("dist/a b+c.tar.gz", false),
+            "dist/a%20b%2Bc.tar.gz"
+        );
+        assert_eq!(uri_encode("mirror/dist/", true), "mirror%2Fdist%2F");
+    }
+
+    #[test]
+    fn parses_paginated_listings() {
+        let first = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <ListBucketResult><Name>b</Name><Prefix>p/</Prefix><KeyCount>2</KeyCount>\
+            <IsTruncated>true</IsTruncated>\
+            <NextContinuationToken>1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=</NextContinuationToken>\
+            <Contents><Key>p/dist/channel-rust-stable.toml</Key><Size>1024</Size></Contents>\
+            <Contents><Key>p/dist/a&amp;b.tar.gz</Key><Size>7</Size></Contents>\
+            </ListBucketResult>";
+        let (objects, next) = parse_listing(first, "p/").unwrap();
+        assert_eq!(
+            objects,
+            [
+                ("dist/channel-rust-stable.toml".to_string(), 1024),
+                ("dist/a&b.tar.gz".to_string(), 7)
+            ]
+        );
+        assert_eq!(
+            next.as_deref(),
+            Some("1ueGcxLPRx1Tr/XYExHnhbYLgveDs2J/wm36Hy4vbOwM=")
+        );
+
+        let last = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+            <Contents><Key>p/rustup/release-stable.toml</Key><Size>42</Size></Contents>\
+            </ListBucketResult>";
+        let (objects, next) = parse_listing(last, "p/").unwrap();
+        assert_eq!(objects, [("rustup/release-stable.toml".to_string(), 42)]);
+        assert_eq!(next, None);
+
+        let empty = "<ListBucketResult><KeyCount>0</KeyCount><IsTruncated>false</IsTruncated></ListBucketResult>";
+        assert_eq!(parse_listing(empty, "p/").unwrap(), (vec![], None));
+        assert!(parse_listing("<Contents><Size>1</Size></Contents>", "").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use crate::s3::S3Storage;
+use crate::{link_or_copy, usage, StorageArgs};
+use anyhow::Error;
+use std::fs::{
+    create_dir_all, read, read_dir, remove_dir_all, remove_file, rename, symlink_metadata, File,
+};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Where the mirror is published. Paths are relative to the mirror root and
+/// always use `/` as separator.
+pub trait Storage {
+    /// Path of a file on the local filesystem, if the mirror is stored there
+    fn local_path(&self, path: &str) -> Option<PathBuf>;
+
+    /// Content of a file, or None if it does not exist
+    fn get(&self, path: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Publish a local file
+    fn put_file(&self, path: &str, file: &Path) -> Result<(), Error>;
+
+    fn delete(&self, path: &str) -> Result<(), Error>;
+
+    /// Delete a directory and everything below it
+    fn delete_dir(&self, path: &str) -> Result<(), Error>;
+
+    /// Paths of all files below a directory, or of the whole mirror for ""
+    fn list(&self, dir: &str) -> Result<Vec<String>, Error>;
+
+    /// Size of a file in bytes, or None if it does not exist
+    fn file_size(&self, path: &str) -> Result<Option<u64>, Error>;
+
+    /// Total size of the mirror in bytes
+    fn size(&self) -> Result<u64, Error>;
+}
+
+/// Storage selected by the arguments, the `--mirror` directory by default
+pub fn open(mirror_path: &str, args: &StorageArgs) -> Result<Box<dyn Storage>, Error> {
+    match &args.storage {
+        Some(url) => Ok(Box::new(S3Storage::new(
+            url,
+            args.s3_endpoint.as_deref(),
+            args.s3_region.as_deref(),
+        )?)),
+        None => Ok(Box::new(FsStorage::new(mirror_path))),
+    }
+}
+
+/// A mirror in a local directory
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FsStorage { root: root.into() }
+    }
+}
+
+impl Storage for FsStorage {
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        Some(self.root.join(path))
+    }
+
+    fn get(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        match read(self.root.join(path)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let file = self.root.join(path);
+        create_dir_all(file.parent().unwrap())?;
+        // Write aside and rename, so readers never see a partial file and
+        // links to the old content are left alone
+        let part = self.root.join(format!("{}.part", path));
+        File::create(&part)?.write_all(data)?;
+        rename(part, file)?;
+        Ok(())
+    }
+
+    fn put_file(&self, path: &str, file: &Path) -> Result<(), Error> {
+        let to = self.root.join(path);
+        // Files downloaded into the mirror are already in place
+        if to != file {
+            link_or_copy(file, &to)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), Error> {
+        Ok(remove_file(self.root.join(path))?)
+    }
+
+    fn delete_dir(&self, path: &str) -> Result<(), Error> {
+        Ok(remove_dir_all(self.root.join(path))?)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let mut paths = vec![];
+        let mut dirs = vec![dir.trim_end_matches('/').to_string()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match read_dir(self.root.join(&dir)) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let path = match dir.as_str() {
+                    "" => name,
+                    dir => format!("{}/{}", dir, name),
+                };
+                if entry.file_type()?.is_dir() {
+                    dirs.push(path);
+                } else {
+                    paths.push(path);
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    fn file_size(&self, path: &str) -> Result<Option<u64>, Error> {
+        // Links into the content-addressed store count as themselves
+        match symlink_metadata(self.root.join(path)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn size(&self) -> Result<u64, Error> {
+        usage::dir_size(&self.root)
+    }
+}
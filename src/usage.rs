@@ -0,0 +1,128 @@
+use crate::{manifest_artifacts, Cli};
+use anyhow::{Context, Error};
+use indicatif::HumanBytes;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{metadata, read_dir, read_to_string, Metadata};
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Total size of the files below a directory, which may not exist.
+/// Hardlinked files, e.g. in the content-addressed store, count once.
+pub fn dir_size(dir: &Path) -> Result<u64, Error> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    add_dir_size(dir, &mut HashSet::new())
+}
+
+fn add_dir_size(dir: &Path, seen: &mut HashSet<FileId>) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += add_dir_size(&entry.path(), seen)?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+            if seen.insert(file_id(&metadata, &entry.path())) {
+                size += metadata.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(unix)]
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata, _path: &Path) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata, path: &Path) -> FileId {
+    path.to_path_buf()
+}
+
+/// Published channel manifests of the mirror, heads and dated ones, by channel
+fn channel_manifests(mirror: &Path) -> Result<BTreeMap<String, Vec<PathBuf>>, Error> {
+    let mut manifests: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    let dist = mirror.join("dist");
+    if !dist.exists() {
+        return Ok(manifests);
+    }
+    let mut dirs = vec![dist.clone()];
+    for entry in read_dir(&dist)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    for dir in dirs {
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if let Some(channel) = name
+                .strip_prefix("channel-rust-")
+                .and_then(|name| name.strip_suffix(".toml"))
+            {
+                manifests.entry(channel.to_string()).or_default().push(path);
+            }
+        }
+    }
+    Ok(manifests)
+}
+
+/// Report disk usage of the mirror per channel
+pub fn usage(cli: &Cli) -> Result<(), Error> {
+    let mirror = Path::new(&cli.mirror);
+    let mut all = HashSet::new();
+    let mut all_size = 0;
+
+    println!("{:<20} {:>8} {:>12}", "Channel", "Files", "Size");
+    for (channel, manifests) in channel_manifests(mirror)? {
+        let mut files = HashSet::new();
+        for manifest in manifests {
+            let data = read_to_string(&manifest)?;
+            let value: Value = toml::from_str(&data)
+                .with_context(|| format!("Invalid manifest {}", manifest.display()))?;
+            for (path, _) in manifest_artifacts(&value, None)? {
+                files.insert(path);
+            }
+        }
+
+        let mut size = 0;
+        let mut present = 0;
+        for file in &files {
+            // Files shared with another channel are counted for both
+            if let Ok(metadata) = metadata(mirror.join(file)) {
+                size += metadata.len();
+                present += 1;
+                if all.insert(file.clone()) {
+                    all_size += metadata.len();
+                }
+            }
+        }
+        println!(
+            "{:<20} {:>8} {:>12}",
+            channel,
+            present,
+            HumanBytes(size).to_string()
+        );
+    }
+
+    let total = dir_size(mirror)?;
+    println!(
+        "Mirror {} uses {}, {} of it in {} channel files",
+        mirror.display(),
+        HumanBytes(total),
+        HumanBytes(all_size),
+        all.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use crate::lock::MirrorLock;
+use crate::storage::{self, Storage};
+use crate::{
+    cancel, cas, download_verified, file_sha256, init_client, manifest_artifacts, publish_staged,
+    Cli, ProfileArgs, StorageArgs, UpstreamArgs,
+};
+use anyhow::{anyhow, Context, Error};
+use clap::Args;
+use ring::digest;
+use std::collections::BTreeMap;
+use std::path::Path;
+use toml::Value;
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    pub profiles: ProfileArgs,
+
+    #[command(flatten)]
+    pub storage: StorageArgs,
+
+    /// Where --repair downloads files from
+    #[command(flatten)]
+    pub upstream: UpstreamArgs,
+
+    /// Store repaired files by checksum, as --cas does for syncs
+    #[arg(long, value_enum)]
+    pub cas: Option<cas::CasMode>,
+
+    /// Download missing and corrupt files again from the upstream, and rewrite
+    /// checksum files that do not match
+    #[arg(long)]
+    repair: bool,
+}
+
+/// Checksum of a file of the mirror, or None if it does not exist
+pub fn sha256_of(storage: &dyn Storage, path: &str) -> Result<Option<String>, Error> {
+    match storage.local_path(path) {
+        Some(file) => Ok(file_sha256(&file)),
+        None => Ok(storage
+            .get(path)?
+            .map(|data| hex::encode(digest::digest(&digest::SHA256, &data)))),
+    }
+}
+
+/// Rehash every file the published channel manifests refer to, and report
+/// missing and corrupt files and checksum files that do not match
+pub fn verify(cli: &Cli, args: &VerifyArgs) -> Result<(), Error> {
+    let _lock = if args.repair {
+        init_client(&args.upstream)?;
+        Some(MirrorLock::acquire(&cli.mirror, args.storage.wait)?)
+    } else {
+        None
+    };
+    let storage = storage::open(&cli.mirror, &args.storage)?;
+    let mut problems = 0;
+
+    // Manifests are checked against their own checksum file
+    let manifests: Vec<String> = storage
+        .list("dist")?
+        .into_iter()
+        .filter(|path| {
+            path.rsplit('/')
+                .next()
+                .is_some_and(|name| name.starts_with("channel-rust-") && name.ends_with(".toml"))
+        })
+        .collect();
+    let mut artifacts = BTreeMap::new();
+    for manifest in &manifests {
+        cancel::check()?;
+        let Some(data) = storage.get(manifest)? else {
+            continue;
+        };
+        let sha256 = hex::encode(digest::digest(&digest::SHA256, &data));
+        let sidecar = storage.get(&format!("{}.sha256", manifest))?;
+        if sidecar.as_deref().and_then(|sidecar| sidecar.get(..64)) != Some(sha256.as_bytes()) {
+            println!("Checksum file of /{} does not match", manifest);
+            problems += 1;
+        }
+
+        let value: Value = toml::from_str(&String::from_utf8_lossy(&data))
+            .with_context(|| format!("Invalid manifest /{}", manifest))?;
+        for (path, sha256) in manifest_artifacts(&value, None)
+            .with_context(|| format!("Invalid manifest /{}", manifest))?
+        {
+            artifacts.entry(path).or_insert(sha256);
+        }
+    }
+
+    println!(
+        "Verifying {} files referenced by {} manifests...",
+        artifacts.len(),
+        manifests.len()
+    );
+    let (mut missing, mut corrupt, mut mismatched, mut repaired) = (0, 0, 0, 0);
+    for (path, sha256) in &artifacts {
+        cancel::check()?;
+        let sidecar_path = format!("{}.sha256", path);
+        match sha256_of(&*storage, path)? {
+            None => {
+                println!("Missing /{}", path);
+                missing += 1;
+            }
+            Some(actual) if actual != *sha256 => {
+                println!("Corrupt /{}: expected {}, found {}", path, sha256, actual);
+                corrupt += 1;
+            }
+            Some(_) => {
+                let sidecar = storage.get(&sidecar_path)?;
+                if sidecar.is_none_or(|sidecar| String::from_utf8_lossy(&sidecar).trim() != sha256)
+                {
+                    println!("Checksum file of /{} does not match", path);
+                    mismatched += 1;
+                    if args.repair {
+                        storage.put(&sidecar_path, sha256.as_bytes())?;
+                        println!("Writing checksum for file /{}", path);
+                        repaired += 1;
+                    }
+                }
+                continue;
+            }
+        }
+        if !args.repair {
+            continue;
+        }
+        match repair(cli, args, &*storage, path, sha256) {
+            Ok(()) => repaired += 1,
+            Err(err) if cancel::is_cancel_error(&err) => return Err(err),
+            Err(err) => println!("Unable to repair /{}: {:#}", path, err),
+        }
+    }
+    problems += missing + corrupt + mismatched - repaired;
+
+    println!(
+        "Verified {} files: {} missing, {} corrupt, {} checksum files do not match, {} repaired",
+        artifacts.len(),
+        missing,
+        corrupt,
+        mismatched,
+        repaired
+    );
+    if problems > 0 {
+        return Err(anyhow!(
+            "Mirror {} failed verification with {} problems",
+            cli.mirror,
+            problems
+        ));
+    }
+    Ok(())
+}
+
+/// Download a missing or corrupt file again, replacing a corrupt copy in the
+/// content-addressed store too
+fn repair(
+    cli: &Cli,
+    args: &VerifyArgs,
+    storage: &dyn Storage,
+    path: &str,
+    sha256: &str,
+) -> Result<(), Error> {
+    let mirror = Path::new(&cli.mirror);
+    if storage.local_path("").is_some() {
+        cas::drop_corrupt(mirror, sha256)?;
+    }
+    let file = download_verified(&args.upstream.upstream_url, &cli.mirror, path, sha256)?;
+    publish_staged(storage, path, &file)?;
+    if let Some(mode) = args.cas {
+        cas::store(mirror, path, sha256, mode)?;
+    }
+    storage.put(&format!("{}.sha256", path), sha256.as_bytes())?;
+    println!("Repaired /{}", path);
+    Ok(())
+}